@@ -1,3 +1,5 @@
+pub mod validate;
+
 use std::{collections::HashMap, sync::Arc};
 
 use hyper::{http::HeaderValue, Body, Response, StatusCode};
@@ -79,3 +81,25 @@ pub fn parse_query(query: Option<&str>) -> HashMap<&str, &str> {
 
     query_map
 }
+
+/// Splits the path for routing, so `/inventory/1` can be matched with
+/// `["inventory", id]`.
+pub fn path_segments(path: &str) -> Vec<&str> {
+    path.split("/").filter(|s| !s.is_empty()).collect()
+}
+
+/// Replaces any `field` params in the query string with `field=eq-{value}`,
+/// eg to limit a query to the authenticated user's rows.
+pub fn scope_query(query: Option<&str>, field: &str, value: &str) -> String {
+    let mut scoped: Vec<String> = query
+        .unwrap_or("")
+        .split("&")
+        .filter(|q| !q.is_empty())
+        .filter(|q| q.split_once("=").map_or(*q, |(k, _)| k) != field)
+        .map(|q| q.to_owned())
+        .collect();
+
+    scoped.push(format!("{}=eq-{}", field, value));
+
+    scoped.join("&")
+}
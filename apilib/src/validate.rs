@@ -0,0 +1,267 @@
+use hyper::StatusCode;
+use serde_json::json;
+
+/// Longest password accepted, hashing is slow so long ones are refused
+/// before they get that far.
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Shortest password accepted for new passwords.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Why a field was rejected. `code` is for clients to match on, `message` is
+/// for people.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Checks what serde can't once a request body has been deserialized, eg
+/// that a name isn't empty.
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Collects the errors for every invalid field so they can all be sent back
+/// at once. Only the first error for each field is kept.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn error(&mut self, field: &str, code: &'static str, message: String) -> &mut Self {
+        if !self.errors.iter().any(|e| e.field == field) {
+            self.errors.push(FieldError {
+                field: field.to_owned(),
+                code,
+                message,
+            });
+        }
+        self
+    }
+
+    /// Rejects empty and whitespace only values.
+    pub fn required(&mut self, field: &str, value: &str) -> &mut Self {
+        if value.trim().is_empty() {
+            self.error(field, "required", format!("{} is required", field));
+        }
+        self
+    }
+
+    /// Lengths are counted in characters, not bytes.
+    pub fn min_length(&mut self, field: &str, value: &str, min: usize) -> &mut Self {
+        if value.chars().count() < min {
+            self.error(
+                field,
+                "too_short",
+                format!("{} must be at least {} characters", field, min),
+            );
+        }
+        self
+    }
+
+    pub fn max_length(&mut self, field: &str, value: &str, max: usize) -> &mut Self {
+        if value.chars().count() > max {
+            self.error(
+                field,
+                "too_long",
+                format!("{} must be at most {} characters", field, max),
+            );
+        }
+        self
+    }
+
+    /// Only checks the shape, `local@domain.tld`. Whether the address works
+    /// is found out by emailing it.
+    pub fn email(&mut self, field: &str, value: &str) -> &mut Self {
+        let valid = match value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.split('.').count() > 1
+                    && domain.split('.').all(|part| !part.is_empty())
+                    && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+            }
+            None => false,
+        };
+
+        if !valid {
+            self.error(
+                field,
+                "invalid_email",
+                format!("{} is not a valid email address", field),
+            );
+        }
+        self
+    }
+
+    /// For new passwords, checks `MIN_PASSWORD_LENGTH` and
+    /// `MAX_PASSWORD_LENGTH`.
+    pub fn password(&mut self, field: &str, value: &str) -> &mut Self {
+        self.required(field, value)
+            .min_length(field, value, MIN_PASSWORD_LENGTH)
+            .max_length(field, value, MAX_PASSWORD_LENGTH)
+    }
+
+    /// Inclusive of `min` and `max`.
+    pub fn range(&mut self, field: &str, value: i64, min: i64, max: i64) -> &mut Self {
+        if value < min || value > max {
+            self.error(
+                field,
+                "out_of_range",
+                format!("{} must be between {} and {}", field, min, max),
+            );
+        }
+        self
+    }
+
+    /// For IDs, which start at 1.
+    pub fn positive(&mut self, field: &str, value: i64) -> &mut Self {
+        if value < 1 {
+            self.error(field, "out_of_range", format!("{} must be positive", field));
+        }
+        self
+    }
+
+    pub fn one_of(&mut self, field: &str, value: &str, allowed: &[&str]) -> &mut Self {
+        if !allowed.contains(&value) {
+            self.error(
+                field,
+                "invalid_choice",
+                format!("{} must be one of {}", field, allowed.join(", ")),
+            );
+        }
+        self
+    }
+
+    /// Validates a nested object, its errors are reported as `field.inner`.
+    pub fn nested<T: Validate>(&mut self, field: &str, value: &T) -> &mut Self {
+        let mut inner = Validator::default();
+        value.validate(&mut inner);
+
+        for e in inner.errors {
+            let name = format!("{}.{}", field, e.field);
+            let message = e.message.replacen(&e.field, &name, 1);
+            self.error(&name, e.code, message);
+        }
+        self
+    }
+
+    pub fn into_errors(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+/// Returns a 422 with a body like
+/// `{"errors": [{"field": "email", "code": "required", "message": "email is required"}]}`
+/// if any field is invalid.
+pub fn validate<T: Validate>(body: &T) -> Result<(), (StatusCode, Option<serde_json::Value>)> {
+    let mut v = Validator::default();
+    body.validate(&mut v);
+
+    let errors = v.into_errors();
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let errors: Vec<serde_json::Value> = errors
+        .iter()
+        .map(|e| json!({ "field": e.field, "code": e.code, "message": e.message }))
+        .collect();
+
+    Err((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Some(json!({ "errors": errors })),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::{validate, Validate, Validator};
+
+    struct Item {
+        name: String,
+        quantity: i64,
+    }
+
+    impl Validate for Item {
+        fn validate(&self, v: &mut Validator) {
+            v.required("name", &self.name)
+                .max_length("name", &self.name, 5);
+            v.range("quantity", self.quantity, 1, 10);
+        }
+    }
+
+    struct Patch {
+        old: Item,
+        new: Item,
+    }
+
+    impl Validate for Patch {
+        fn validate(&self, v: &mut Validator) {
+            v.nested("old", &self.old).nested("new", &self.new);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let item = Item {
+            name: "pot".into(),
+            quantity: 1,
+        };
+        assert!(validate(&item).is_ok());
+
+        let item = Item {
+            name: " ".into(),
+            quantity: 0,
+        };
+        let (code, body) = validate(&item).unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body.unwrap(),
+            json!({ "errors": [
+                { "field": "name", "code": "required", "message": "name is required" },
+                { "field": "quantity", "code": "out_of_range", "message": "quantity must be between 1 and 10" },
+            ]})
+        );
+
+        let patch = Patch {
+            old: Item {
+                name: "pot".into(),
+                quantity: 1,
+            },
+            new: Item {
+                name: "teapot".into(),
+                quantity: 1,
+            },
+        };
+        let (_, body) = validate(&patch).unwrap_err();
+        assert_eq!(
+            body.unwrap()["errors"][0],
+            json!({ "field": "new.name", "code": "too_long", "message": "new.name must be at most 5 characters" })
+        );
+    }
+
+    #[test]
+    fn test_email() {
+        let check = |email: &str| {
+            let mut v = Validator::default();
+            v.email("email", email);
+            v.into_errors().is_empty()
+        };
+
+        assert!(check("bob@smith.com"));
+        assert!(check("bob+shop@mail.smith.co.uk"));
+        assert!(!check("bob"));
+        assert!(!check("@smith.com"));
+        assert!(!check("bob@smith"));
+        assert!(!check("bob@smith..com"));
+        assert!(!check("bob@@smith.com"));
+        assert!(!check("bob smith@smith.com"));
+    }
+}
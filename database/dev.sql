@@ -0,0 +1,9 @@
+-- Dev only data, not part of init.sql. Run with `make seed` once the
+-- database is up.
+
+\c users
+
+-- bob, from users.sql, can use the admin endpoints once 2FA is on for them:
+INSERT INTO roles (user_id, role) VALUES
+(1, 'admin')
+ON CONFLICT DO NOTHING;
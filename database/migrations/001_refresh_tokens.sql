@@ -0,0 +1,16 @@
+-- Refresh tokens, rotated on every use.
+
+\c users
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id         UUID,
+    family_id  UUID NOT NULL,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    token_hash BYTEA UNIQUE NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at    TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_id ON refresh_tokens (family_id);
@@ -0,0 +1,11 @@
+-- Roles on top of the default user role. Admins are granted by hand, see
+-- dev.sql.
+
+\c users
+
+CREATE TABLE IF NOT EXISTS roles (
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    role       VARCHAR(20),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (user_id, role)
+);
@@ -0,0 +1,13 @@
+-- One-time password reset tokens.
+
+\c users
+
+CREATE TABLE IF NOT EXISTS password_resets (
+    token_hash BYTEA,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at    TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (token_hash)
+);
+CREATE INDEX IF NOT EXISTS password_resets_user_id ON password_resets (user_id);
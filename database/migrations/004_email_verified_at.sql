@@ -0,0 +1,6 @@
+-- Existing users start unverified, they can ask for a link with
+-- POST /verify-email/resend.
+
+\c users
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMPTZ;
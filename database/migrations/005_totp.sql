@@ -0,0 +1,21 @@
+-- Two-factor authentication.
+
+\c users
+
+CREATE TABLE IF NOT EXISTS totp (
+    user_id      BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    secret       BYTEA NOT NULL,
+    last_step    BIGINT,
+    confirmed_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    code_hash  BYTEA,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    used_at    TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (code_hash)
+);
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id ON recovery_codes (user_id);
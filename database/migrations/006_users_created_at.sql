@@ -0,0 +1,5 @@
+-- Existing users get the time this runs, when they signed up wasn't kept.
+
+\c users
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ DEFAULT NOW();
@@ -0,0 +1,49 @@
+-- The audit log, and the function and rules that keep it append only.
+
+\c users
+
+-- Append only, events are kept after the user is deleted, without their
+-- personal data, see erase_auth_events:
+CREATE TABLE IF NOT EXISTS auth_events (
+    id         BIGSERIAL,
+    user_id    BIGINT,
+    event      VARCHAR(20) NOT NULL,
+    email      VARCHAR(100),
+    ip         VARCHAR(45),
+    user_agent VARCHAR(255),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS auth_events_user_id ON auth_events (user_id);
+CREATE INDEX IF NOT EXISTS auth_events_created_at ON auth_events (created_at);
+
+-- Clears the personal data in a user's events, including failed logins with
+-- their email. The app should only need EXECUTE on this, not UPDATE on the
+-- table:
+CREATE OR REPLACE FUNCTION erase_auth_events(target_id BIGINT, target_email VARCHAR)
+RETURNS VOID
+LANGUAGE sql
+SECURITY DEFINER
+SET search_path = public
+AS $$
+    UPDATE auth_events SET email = NULL, ip = NULL, user_agent = NULL
+    WHERE user_id = target_id OR LOWER(email) = LOWER(target_email);
+$$;
+
+-- The only update let through is erase_auth_events', which runs as the
+-- function's owner and only clears an event's email, IP and user agent.
+-- Everything else about the event is kept:
+CREATE OR REPLACE RULE auth_events_no_update AS ON UPDATE TO auth_events
+    WHERE NOT (
+        current_user = (
+            SELECT pg_get_userbyid(proowner) FROM pg_proc
+            WHERE oid = 'erase_auth_events(BIGINT, VARCHAR)'::regprocedure
+        )
+        AND NEW.email IS NULL AND NEW.ip IS NULL AND NEW.user_agent IS NULL
+        AND NEW.id = OLD.id
+        AND NEW.user_id IS NOT DISTINCT FROM OLD.user_id
+        AND NEW.event = OLD.event
+        AND NEW.created_at IS NOT DISTINCT FROM OLD.created_at
+    )
+    DO INSTEAD NOTHING;
+CREATE OR REPLACE RULE auth_events_no_delete AS ON DELETE TO auth_events DO INSTEAD NOTHING;
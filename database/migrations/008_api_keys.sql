@@ -0,0 +1,15 @@
+-- Scoped API keys for scripts and integrations, only their hash is stored.
+
+\c users
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id           UUID,
+    name         VARCHAR(100) NOT NULL,
+    scopes       VARCHAR(20)[] NOT NULL,
+    key_hash     BYTEA UNIQUE NOT NULL,
+    created_by   BIGINT REFERENCES "users" (id) ON DELETE SET NULL,
+    expires_at   TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
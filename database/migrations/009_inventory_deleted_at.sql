@@ -0,0 +1,5 @@
+-- Soft deletes for products.
+
+\c shop
+
+ALTER TABLE inventory ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- Full-text search over product names and descriptions.
+
+\c shop
+
+ALTER TABLE inventory ADD COLUMN IF NOT EXISTS search TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', COALESCE(name, '')), 'A') ||
+    setweight(to_tsvector('english', COALESCE(description, '')), 'B')
+) STORED;
+CREATE INDEX IF NOT EXISTS inventory_search ON inventory USING GIN (search);
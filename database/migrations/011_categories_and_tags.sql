@@ -0,0 +1,28 @@
+-- Nested categories and tags. Existing products have neither.
+
+\c shop
+
+CREATE TABLE IF NOT EXISTS categories(
+    id        BIGSERIAL,
+    parent_id BIGINT REFERENCES "categories" (id),
+    name      VARCHAR(100) NOT NULL,
+    slug      VARCHAR(100) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS categories_parent_id ON categories (parent_id);
+
+ALTER TABLE inventory ADD COLUMN IF NOT EXISTS category_id BIGINT REFERENCES "categories" (id);
+CREATE INDEX IF NOT EXISTS inventory_category_id ON inventory (category_id);
+
+CREATE TABLE IF NOT EXISTS tags(
+    id   BIGSERIAL,
+    name VARCHAR(50) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS inventory_tags(
+    inventory_id BIGINT REFERENCES "inventory" (id) ON DELETE CASCADE,
+    tag_id       BIGINT REFERENCES "tags" (id) ON DELETE CASCADE,
+    PRIMARY KEY (inventory_id, tag_id)
+);
+CREATE INDEX IF NOT EXISTS inventory_tags_tag_id ON inventory_tags (tag_id);
@@ -0,0 +1,55 @@
+-- Moves products' price and stock to variants. Each product gets one
+-- variant with the product's price and stock, with a SKU of SKU-<product id>
+-- to be renamed later. Order items point at that variant, and their
+-- unit_price is the product's price when this runs, as the price paid wasn't
+-- kept before. Items whose product row is gone are left without a variant.
+-- Does nothing once inventory has no price column.
+
+\c shop
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT FROM information_schema.columns
+        WHERE table_name = 'inventory' AND column_name = 'price'
+    ) THEN
+        RETURN;
+    END IF;
+
+    CREATE TABLE variants(
+        id           BIGSERIAL,
+        inventory_id BIGINT NOT NULL REFERENCES "inventory" (id),
+        sku          VARCHAR(64) UNIQUE NOT NULL,
+        price        INT NOT NULL,
+        quantity     INT NOT NULL,
+        size         VARCHAR(50),
+        weight_grams INT,
+        deleted_at   TIMESTAMPTZ,
+        CONSTRAINT   variants_quantity CHECK (quantity >= 0),
+        PRIMARY KEY (id)
+    );
+    CREATE INDEX variants_inventory_id ON variants (inventory_id);
+
+    -- Deleted products keep a deleted variant, past orders still need it:
+    INSERT INTO variants (inventory_id, sku, price, quantity, deleted_at)
+    SELECT id, 'SKU-' || id, COALESCE(price, 0), COALESCE(quantity, 0), deleted_at
+    FROM inventory
+    ORDER BY id;
+
+    ALTER TABLE order_items RENAME COLUMN inventory_id TO variant_id;
+    ALTER TABLE order_items ADD COLUMN unit_price INT;
+
+    UPDATE order_items SET variant_id = variants.id, unit_price = variants.price
+    FROM variants
+    WHERE variants.inventory_id = order_items.variant_id;
+
+    -- Items whose product is gone can't be priced, and their old inventory
+    -- ID could be any variant's:
+    UPDATE order_items SET variant_id = NULL, unit_price = 0 WHERE unit_price IS NULL;
+    ALTER TABLE order_items ALTER COLUMN unit_price SET NOT NULL;
+    ALTER TABLE order_items
+        ADD FOREIGN KEY (variant_id) REFERENCES "variants" (id);
+
+    ALTER TABLE inventory DROP COLUMN price, DROP COLUMN quantity;
+END
+$$;
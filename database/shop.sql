@@ -3,17 +3,58 @@ WHERE NOT EXISTS (SELECT FROM pg_database WHERE datname = 'shop')\gexec
 
 \c shop
 
+CREATE TABLE IF NOT EXISTS categories(
+    id        BIGSERIAL,
+    parent_id BIGINT REFERENCES "categories" (id),
+    name      VARCHAR(100) NOT NULL,
+    slug      VARCHAR(100) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX categories_parent_id ON categories (parent_id);
+
 CREATE TABLE IF NOT EXISTS inventory(
     id          BIGSERIAL,
     name        VARCHAR(100),
-    price       INT,
-    quantity    INT,
     image_url   VARCHAR(255),
     description TEXT,
+    category_id BIGINT REFERENCES "categories" (id),
     created_at  TIMESTAMPTZ DEFAULT NOW(),
-    CONSTRAINT  inventory_quantity CHECK (quantity >= 0),
+    deleted_at  TIMESTAMPTZ,
+    search      TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', COALESCE(name, '')), 'A') ||
+        setweight(to_tsvector('english', COALESCE(description, '')), 'B')
+    ) STORED,
+    PRIMARY KEY (id)
+);
+CREATE INDEX inventory_search ON inventory USING GIN (search);
+CREATE INDEX inventory_category_id ON inventory (category_id);
+
+CREATE TABLE IF NOT EXISTS variants(
+    id           BIGSERIAL,
+    inventory_id BIGINT NOT NULL REFERENCES "inventory" (id),
+    sku          VARCHAR(64) UNIQUE NOT NULL,
+    price        INT NOT NULL,
+    quantity     INT NOT NULL,
+    size         VARCHAR(50),
+    weight_grams INT,
+    deleted_at   TIMESTAMPTZ,
+    CONSTRAINT   variants_quantity CHECK (quantity >= 0),
     PRIMARY KEY (id)
 );
+CREATE INDEX variants_inventory_id ON variants (inventory_id);
+
+CREATE TABLE IF NOT EXISTS tags(
+    id   BIGSERIAL,
+    name VARCHAR(50) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS inventory_tags(
+    inventory_id BIGINT REFERENCES "inventory" (id) ON DELETE CASCADE,
+    tag_id       BIGINT REFERENCES "tags" (id) ON DELETE CASCADE,
+    PRIMARY KEY (inventory_id, tag_id)
+);
+CREATE INDEX inventory_tags_tag_id ON inventory_tags (tag_id);
 
 CREATE TABLE IF NOT EXISTS address(
     id         BIGSERIAL,
@@ -48,18 +89,40 @@ CREATE TABLE IF NOT EXISTS orders(
 );
 CREATE INDEX orders_user_id ON orders (user_id);
 
+-- unit_price is the variant's price when the order was made, so totals don't
+-- change with it:
 CREATE TABLE IF NOT EXISTS order_items(
     order_id UUID REFERENCES "orders" (id),
-    inventory_id BIGINT,
-    quantity INT
+    variant_id BIGINT REFERENCES "variants" (id),
+    quantity INT,
+    unit_price INT NOT NULL
 );
 
 INSERT INTO address (user_id, first_name, last_name, address_1, address_2, postcode, city) VALUES 
 (1, 'bob', 'smith', '1 bob st', '', 'm1abc', 'manchester');
 
-INSERT INTO inventory (name, price, quantity, image_url, description) VALUES
-('Clipper Earl Grey - 80 Teabags', 299, 100, 'https://digitalcontent.api.tesco.com/v2/media/ghs/06da6f5a-c9cc-4c1e-aa3b-4491ab29e3d8/a8e9adb6-5e21-42cb-9876-24ca40a1d269_150922527.jpeg?h=540&w=540', '80 Unbleached, plastic-free bags of organic earl grey tea'),
-('Twining English Breakfast - 160 Teabags', 600, 100, 'https://assets.sainsburys-groceries.co.uk/gol/7975122/1/640x640.jpg', 'Golden and well rounded. Its a tea with a lot of body and a light finish');
+INSERT INTO categories (parent_id, name, slug) VALUES
+(NULL, 'Tea', 'tea'),
+(1, 'Black tea', 'black-tea'),
+(2, 'Earl Grey', 'earl-grey'),
+(2, 'English Breakfast', 'english-breakfast');
+
+INSERT INTO inventory (name, image_url, description, category_id) VALUES
+('Clipper Earl Grey', 'https://digitalcontent.api.tesco.com/v2/media/ghs/06da6f5a-c9cc-4c1e-aa3b-4491ab29e3d8/a8e9adb6-5e21-42cb-9876-24ca40a1d269_150922527.jpeg?h=540&w=540', '80 Unbleached, plastic-free bags of organic earl grey tea', 3),
+('Twinings English Breakfast', 'https://assets.sainsburys-groceries.co.uk/gol/7975122/1/640x640.jpg', 'Golden and well rounded. Its a tea with a lot of body and a light finish', 4);
+
+INSERT INTO variants (inventory_id, sku, price, quantity, size, weight_grams) VALUES
+(1, 'CLIPPER-EG-80', 299, 100, '80 bags', 200),
+(2, 'TWININGS-EB-160', 600, 100, '160 bags', 500),
+(1, 'CLIPPER-EG-40', 175, 100, '40 bags', 100);
+
+INSERT INTO tags (name) VALUES
+('organic'),
+('plastic-free');
+
+INSERT INTO inventory_tags (inventory_id, tag_id) VALUES
+(1, 1),
+(1, 2);
 
 -- BEGIN;
 --     DO $$
@@ -76,9 +139,9 @@ BEGIN;
     DECLARE order_id UUID = gen_random_uuid();
     BEGIN
         INSERT INTO orders VALUES (order_id, 1, 'PENDING', 1);
-        INSERT INTO order_items VALUES (order_id, 1, 1);
-        INSERT INTO order_items VALUES (order_id, 2, 2);
-        UPDATE inventory SET quantity = quantity - 1 WHERE id IN (1, 2);
+        INSERT INTO order_items VALUES (order_id, 1, 1, 299);
+        INSERT INTO order_items VALUES (order_id, 2, 2, 600);
+        UPDATE variants SET quantity = quantity - 1 WHERE id IN (1, 2);
     END
     $$;
 COMMIT;
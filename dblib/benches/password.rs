@@ -0,0 +1,97 @@
+//! Hashes passwords concurrently, like a burst of logins, while a ticker
+//! measures how late its sleeps wake up. Hashing on the executor holds up
+//! every other task on it, `Password::hash_async` shouldn't.
+//!
+//! Run with `cargo bench -p dblib`.
+
+use dblib::users::users::Password;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const LOGINS: usize = 32;
+const TICK: Duration = Duration::from_millis(1);
+
+/// Returns the worst and average lateness of the ticker while `logins` runs.
+async fn lateness<F>(logins: F) -> (Duration, Duration)
+where
+    F: std::future::Future<Output = ()>,
+{
+    let done = Arc::new(AtomicBool::new(false));
+
+    let ticker = tokio::spawn({
+        let done = done.clone();
+        async move {
+            let mut late = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                tokio::time::sleep(TICK).await;
+                late.push(start.elapsed().saturating_sub(TICK));
+            }
+            late
+        }
+    });
+
+    logins.await;
+    done.store(true, Ordering::Relaxed);
+
+    let late = ticker.await.unwrap();
+    let max = late.iter().max().copied().unwrap_or_default();
+    let avg = late.iter().sum::<Duration>() / late.len().max(1) as u32;
+
+    (max, avg)
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let start = Instant::now();
+        let (max, avg) = lateness(async {
+            let tasks: Vec<_> = (0..LOGINS)
+                .map(|_| tokio::spawn(async { Password::hash("password123").unwrap() }))
+                .collect();
+            for t in tasks {
+                t.await.unwrap();
+            }
+        })
+        .await;
+        println!(
+            "inline:     {} hashes in {:?}, ticker late by max {:?} avg {:?}",
+            LOGINS,
+            start.elapsed(),
+            max,
+            avg
+        );
+
+        let start = Instant::now();
+        let (max, avg) = lateness(async {
+            let tasks: Vec<_> = (0..LOGINS)
+                .map(|_| {
+                    tokio::spawn(async {
+                        Password::hash_async("password123".into()).await.unwrap()
+                    })
+                })
+                .collect();
+            for t in tasks {
+                t.await.unwrap();
+            }
+        })
+        .await;
+        println!(
+            "hash_async: {} hashes in {:?}, ticker late by max {:?} avg {:?}",
+            LOGINS,
+            start.elapsed(),
+            max,
+            avg
+        );
+    });
+}
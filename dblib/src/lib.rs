@@ -36,6 +36,19 @@ where
     serializer.serialize_str(&chrono::DateTime::to_rfc3339(dt))
 }
 
+pub fn serialize_opt_dt<S>(
+    dt: &Option<chrono::DateTime<chrono::Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match dt {
+        Some(dt) => serialize_dt(dt, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseError;
 
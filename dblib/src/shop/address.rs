@@ -80,4 +80,11 @@ impl Address {
 
         Ok(query.fetch_all(pool).await.map_err(|e| Either::Left(e))?)
     }
+
+    pub async fn from_user_id(pool: &PgPool, user_id: i64) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM address WHERE user_id = $1 ORDER BY id")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
 }
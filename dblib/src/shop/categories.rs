@@ -0,0 +1,49 @@
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+
+#[derive(Serialize)]
+pub struct Category {
+    id: i64,
+    name: String,
+    slug: String,
+    children: Vec<Category>,
+}
+
+#[derive(FromRow)]
+struct CategoryRow {
+    id: i64,
+    parent_id: Option<i64>,
+    name: String,
+    slug: String,
+}
+
+impl Category {
+    /// Every category, nested under its parent.
+    pub async fn tree(pool: &PgPool) -> Result<Vec<Category>, sqlx::Error> {
+        let mut rows =
+            sqlx::query_as("SELECT id, parent_id, name, slug FROM categories ORDER BY name")
+                .fetch_all(pool)
+                .await?;
+
+        Ok(children_of(None, &mut rows))
+    }
+}
+
+/// Takes the children of `parent` out of `rows`, with their own children
+/// nested under them.
+fn children_of(parent: Option<i64>, rows: &mut Vec<CategoryRow>) -> Vec<Category> {
+    let (children, rest): (Vec<_>, Vec<_>) = std::mem::take(rows)
+        .into_iter()
+        .partition(|c| c.parent_id == parent);
+    *rows = rest;
+
+    children
+        .into_iter()
+        .map(|c| Category {
+            id: c.id,
+            children: children_of(Some(c.id), rows),
+            name: c.name,
+            slug: c.slug,
+        })
+        .collect()
+}
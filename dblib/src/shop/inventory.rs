@@ -1,32 +1,381 @@
 use convert_case::Case;
 use query::{sql::QueryBuilder, sqlx_bind, UrlQuery};
 use serde::Serialize;
-use sqlx::{types::chrono, Either, FromRow, PgPool};
+use sqlx::{
+    postgres::PgArguments, query::QueryAs, types::chrono, Either, FromRow, PgPool, Postgres,
+    Transaction,
+};
 
 use crate::{serialize_dt, ParseError};
 
+/// The lowest price and the total stock of a product's variants, selected
+/// alongside `inventory.*`.
+const STOCK: &str = "\
+    (SELECT MIN(price) FROM variants WHERE variants.inventory_id = inventory.id \
+    AND variants.deleted_at IS NULL) AS price, \
+    (SELECT COALESCE(SUM(quantity), 0)::INT FROM variants \
+    WHERE variants.inventory_id = inventory.id AND variants.deleted_at IS NULL) AS quantity";
+
+/// What listings give `QueryBuilder`, only the clauses it adds after this
+/// are used.
+const BUILDER_BASE: &str = "SELECT * FROM inventory";
+
+/// The clauses `QueryBuilder` added after `BUILDER_BASE`. Anything else is
+/// a bug rather than a bad query, so it's a server error.
+fn builder_clauses(sql: &str) -> Result<&str, Either<sqlx::Error, ParseError>> {
+    sql.strip_prefix(BUILDER_BASE).ok_or_else(|| {
+        Either::Left(sqlx::Error::Protocol(format!(
+            "query builder output doesn't start with {:?}: {}",
+            BUILDER_BASE, sql
+        )))
+    })
+}
+
 #[derive(Serialize, FromRow)]
 pub struct Inventory {
     id: i64,
     name: String,
-    price: i32,
+    /// The lowest price of the product's variants, None if it has none.
+    price: Option<i32>,
+    /// The stock of all the product's variants.
     quantity: i32,
     #[serde(rename(serialize = "imageUrl"))]
     image_url: String,
     description: String,
+    #[serde(rename(serialize = "categoryId"))]
+    category_id: Option<i64>,
     #[serde(serialize_with = "serialize_dt", rename(serialize = "createdAt"))]
     created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// One of the ways a product is sold, eg 40 or 80 teabags, with its own
+/// price and stock.
+#[derive(Serialize, FromRow)]
+pub struct Variant {
+    id: i64,
+    sku: String,
+    price: i32,
+    quantity: i32,
+    size: Option<String>,
+    #[serde(rename(serialize = "weightGrams"))]
+    weight_grams: Option<i32>,
+}
+
+pub struct NewVariant<'a> {
+    pub sku: &'a str,
+    pub price: i32,
+    pub quantity: i32,
+    pub size: Option<&'a str>,
+    pub weight_grams: Option<i32>,
+}
+
+/// The fields of a variant to set, the rest are left as they are.
+#[derive(Default)]
+pub struct VariantChanges<'a> {
+    pub sku: Option<&'a str>,
+    pub price: Option<i32>,
+    pub quantity: Option<i32>,
+    pub size: Option<&'a str>,
+    pub weight_grams: Option<i32>,
+}
+
+/// A product with the variants it's sold in.
+#[derive(Serialize)]
+pub struct Product {
+    #[serde(flatten)]
+    inventory: Inventory,
+    variants: Vec<Variant>,
+    tags: Vec<String>,
+}
+
+/// A product matching a `search`, ranked by how well it matches.
+#[derive(Serialize, FromRow)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    inventory: Inventory,
+    rank: f32,
+    /// `name` and `description` with the matched terms in `<b>` tags.
+    #[serde(rename(serialize = "nameHighlight"))]
+    name_highlight: String,
+    #[serde(rename(serialize = "descriptionHighlight"))]
+    description_highlight: String,
+}
+
+/// Narrows the products listed beyond what `UrlQuery` can express.
+#[derive(Default)]
+pub struct Filter {
+    /// Takes the syntax of web search boxes, eg `"earl grey" -decaf`.
+    pub search: Option<String>,
+    /// A category's slug, products in its subcategories match too.
+    pub category: Option<String>,
+    /// Products need all of them.
+    pub tags: Vec<String>,
+}
+
+impl Filter {
+    /// `FROM` and `WHERE` for the products that match, with params numbered
+    /// from `first` and bound by `bind`. The search query is `q`.
+    fn from_where(&self, first: usize) -> String {
+        let mut n = first;
+        let mut sql = String::from("FROM inventory");
+
+        if self.search.is_some() {
+            sql.push_str(&format!(", websearch_to_tsquery('english', ${}) AS q", n));
+            n += 1;
+        }
+
+        sql.push_str(" WHERE inventory.deleted_at IS NULL");
+
+        if self.search.is_some() {
+            sql.push_str(" AND inventory.search @@ q");
+        }
+
+        if self.category.is_some() {
+            sql.push_str(&format!(
+                " AND inventory.category_id IN (\
+                WITH RECURSIVE subtree AS (\
+                SELECT id FROM categories WHERE slug = ${} \
+                UNION \
+                SELECT categories.id FROM categories \
+                JOIN subtree ON categories.parent_id = subtree.id\
+                ) SELECT id FROM subtree)",
+                n
+            ));
+            n += 1;
+        }
+
+        if !self.tags.is_empty() {
+            sql.push_str(&format!(
+                " AND inventory.id IN (\
+                SELECT inventory_id FROM inventory_tags \
+                JOIN tags ON tags.id = inventory_tags.tag_id \
+                WHERE tags.name = ANY(${0}) \
+                GROUP BY inventory_id HAVING COUNT(*) = cardinality(${0}))",
+                n
+            ));
+        }
+
+        sql
+    }
+
+    fn bind<'q, O>(
+        &'q self,
+        mut query: QueryAs<'q, Postgres, O, PgArguments>,
+    ) -> QueryAs<'q, Postgres, O, PgArguments> {
+        if let Some(search) = &self.search {
+            query = query.bind(search.as_str());
+        }
+        if let Some(category) = &self.category {
+            query = query.bind(category.as_str());
+        }
+        if !self.tags.is_empty() {
+            query = query.bind(self.tags.as_slice());
+        }
+        query
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct CategoryCount {
+    id: i64,
+    #[serde(rename(serialize = "parentId"))]
+    parent_id: Option<i64>,
+    name: String,
+    slug: String,
+    count: i64,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct TagCount {
+    name: String,
+    count: i64,
+}
+
+/// How many products match in each category and with each tag, for
+/// storefront filters. Categories count the products in their
+/// subcategories too.
+#[derive(Serialize)]
+pub struct Facets {
+    categories: Vec<CategoryCount>,
+    tags: Vec<TagCount>,
+}
+
+impl Facets {
+    /// Counts the products matching `filter`, `UrlQuery` filters and paging
+    /// aren't applied.
+    pub async fn get(pool: &PgPool, filter: &Filter) -> Result<Facets, sqlx::Error> {
+        let sql = format!(
+            r#"
+            WITH RECURSIVE tree AS (
+                SELECT id AS ancestor, id AS descendant FROM categories
+                UNION
+                SELECT tree.ancestor, categories.id FROM categories
+                JOIN tree ON categories.parent_id = tree.descendant
+            ), matched AS (
+                SELECT inventory.category_id {}
+            )
+            SELECT categories.id, categories.parent_id, categories.name, categories.slug,
+            COUNT(*) AS count FROM matched
+            JOIN tree ON matched.category_id = tree.descendant
+            JOIN categories ON categories.id = tree.ancestor
+            GROUP BY categories.id
+            ORDER BY categories.name
+            "#,
+            filter.from_where(1)
+        );
+        let categories = filter.bind(sqlx::query_as(&sql)).fetch_all(pool).await?;
+
+        let sql = format!(
+            r#"
+            WITH matched AS (
+                SELECT inventory.id {}
+            )
+            SELECT tags.name, COUNT(*) AS count FROM matched
+            JOIN inventory_tags ON inventory_tags.inventory_id = matched.id
+            JOIN tags ON tags.id = inventory_tags.tag_id
+            GROUP BY tags.name
+            ORDER BY count DESC, tags.name
+            "#,
+            filter.from_where(1)
+        );
+        let tags = filter.bind(sqlx::query_as(&sql)).fetch_all(pool).await?;
+
+        Ok(Facets { categories, tags })
+    }
+}
+
 impl Inventory {
+    /// Adds the product with its variants and tags, a SKU that's taken adds
+    /// none of them. Tags that don't exist yet are created.
+    pub async fn new(
+        pool: &PgPool,
+        name: &str,
+        image_url: &str,
+        description: &str,
+        category_id: Option<i64>,
+        tags: &[String],
+        variants: &[NewVariant<'_>],
+    ) -> Result<Product, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO inventory (name, image_url, description, category_id)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(name)
+        .bind(image_url)
+        .bind(description)
+        .bind(category_id)
+        .fetch_one(&mut tx)
+        .await?;
+
+        set_tags(&mut tx, id, tags).await?;
+
+        for variant in variants {
+            sqlx::query(
+                r#"
+                INSERT INTO variants (inventory_id, sku, price, quantity, size, weight_grams)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(id)
+            .bind(variant.sku)
+            .bind(variant.price)
+            .bind(variant.quantity)
+            .bind(variant.size)
+            .bind(variant.weight_grams)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Product::from_id(pool, id).await
+    }
+
+    /// Sets the fields that are `Some`, `tags` replaces the product's tags
+    /// and `Some(None)` takes the product out of its category. Returns None if
+    /// there is no product with the ID, or it was deleted.
+    pub async fn update(
+        pool: &PgPool,
+        id: i64,
+        name: Option<&str>,
+        image_url: Option<&str>,
+        description: Option<&str>,
+        category_id: Option<Option<i64>>,
+        tags: Option<&[String]>,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE inventory SET
+            name = COALESCE($2, name),
+            image_url = COALESCE($3, image_url),
+            description = COALESCE($4, description),
+            category_id = CASE WHEN $5 THEN $6 ELSE category_id END
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(name)
+        .bind(image_url)
+        .bind(description)
+        .bind(category_id.is_some())
+        .bind(category_id.flatten())
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        if let Some(tags) = tags {
+            set_tags(&mut tx, id, tags).await?;
+        }
+
+        tx.commit().await?;
+
+        Product::from_id(pool, id).await.map(Some)
+    }
+
+    /// Hides the product rather than deleting the row, which past orders
+    /// still need for their totals. Returns false if there is no product with
+    /// the ID, or it was already deleted.
+    pub async fn delete(pool: &PgPool, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE inventory SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Deleted products are left out.
     pub async fn get(
         pool: &PgPool,
+        filter: &Filter,
         query: UrlQuery,
     ) -> Result<Vec<Inventory>, Either<sqlx::Error, ParseError>> {
-        let (sql, args) = QueryBuilder::from_str("SELECT * FROM inventory", query)
+        let (clauses, args) = QueryBuilder::from_str(BUILDER_BASE, query)
             .convert_case(Case::Snake)
             .build();
 
+        // The query's clauses apply to the products with their stock, the
+        // filter's params are bound after the query's own:
+        let sql = format!(
+            "SELECT * FROM (SELECT inventory.*, {} {}) AS inventory{}",
+            STOCK,
+            filter.from_where(args.len() + 1),
+            builder_clauses(&clauses)?
+        );
+
         let mut query = sqlx::query_as(&sql);
 
         sqlx_bind! (
@@ -35,9 +384,206 @@ impl Inventory {
             "id" => i64,
             "quantity" => i32,
             "price" => i32,
-            "createdAt" => String
+            "createdAt" => chrono::DateTime<chrono::Utc>
         );
 
-        Ok(query.fetch_all(pool).await.map_err(|e| Either::Left(e))?)
+        filter
+            .bind(query)
+            .fetch_all(pool)
+            .await
+            .map_err(Either::Left)
+    }
+
+    /// Full-text search over `name` and `description` for `filter.search`,
+    /// which must be set. Sort by `rank` for the best matches first. Deleted
+    /// products are left out.
+    pub async fn search(
+        pool: &PgPool,
+        filter: &Filter,
+        query: UrlQuery,
+    ) -> Result<Vec<SearchResult>, Either<sqlx::Error, ParseError>> {
+        let (clauses, args) = QueryBuilder::from_str(BUILDER_BASE, query)
+            .convert_case(Case::Snake)
+            .build();
+
+        // Like `get`, the filter's params are bound after the query's own:
+        let sql = format!(
+            "SELECT * FROM (\
+            SELECT inventory.*, {}, ts_rank(inventory.search, q) AS rank, \
+            ts_headline('english', COALESCE(name, ''), q) AS name_highlight, \
+            ts_headline('english', COALESCE(description, ''), q) AS description_highlight \
+            {}\
+            ) AS inventory{}",
+            STOCK,
+            filter.from_where(args.len() + 1),
+            builder_clauses(&clauses)?
+        );
+
+        let mut query = sqlx::query_as(&sql);
+
+        sqlx_bind! (
+            args => query,
+            error: Either::Right(ParseError),
+            "id" => i64,
+            "quantity" => i32,
+            "price" => i32,
+            "createdAt" => chrono::DateTime<chrono::Utc>
+        );
+
+        filter
+            .bind(query)
+            .fetch_all(pool)
+            .await
+            .map_err(Either::Left)
+    }
+}
+
+impl Product {
+    /// Deleted products and variants aren't found.
+    pub async fn from_id(pool: &PgPool, id: i64) -> Result<Product, sqlx::Error> {
+        let inventory = sqlx::query_as(&format!(
+            "SELECT inventory.*, {} FROM inventory WHERE id = $1 AND deleted_at IS NULL",
+            STOCK
+        ))
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        let variants = sqlx::query_as(
+            "SELECT * FROM variants WHERE inventory_id = $1 AND deleted_at IS NULL ORDER BY id",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        let tags = sqlx::query_scalar(
+            r#"
+            SELECT tags.name FROM tags
+            JOIN inventory_tags ON inventory_tags.tag_id = tags.id
+            WHERE inventory_tags.inventory_id = $1
+            ORDER BY tags.name
+            "#,
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Product {
+            inventory,
+            variants,
+            tags,
+        })
+    }
+}
+
+/// Replaces the product's tags, creating the ones that don't exist yet.
+async fn set_tags(
+    tx: &mut Transaction<'_, Postgres>,
+    inventory_id: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM inventory_tags WHERE inventory_id = $1")
+        .bind(inventory_id)
+        .execute(&mut *tx)
+        .await?;
+
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query(
+        "INSERT INTO tags (name) SELECT unnest($1::VARCHAR[]) ON CONFLICT (name) DO NOTHING",
+    )
+    .bind(tags)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO inventory_tags (inventory_id, tag_id)
+        SELECT $1, id FROM tags WHERE name = ANY($2)
+        "#,
+    )
+    .bind(inventory_id)
+    .bind(tags)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+impl Variant {
+    /// Returns None if there is no product with the ID, or it was deleted.
+    pub async fn new(
+        pool: &PgPool,
+        inventory_id: i64,
+        variant: &NewVariant<'_>,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            INSERT INTO variants (inventory_id, sku, price, quantity, size, weight_grams)
+            SELECT id, $2, $3, $4, $5, $6 FROM inventory
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING *
+            "#,
+        )
+        .bind(inventory_id)
+        .bind(variant.sku)
+        .bind(variant.price)
+        .bind(variant.quantity)
+        .bind(variant.size)
+        .bind(variant.weight_grams)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Sets the fields that are `Some`. Returns None if the product has no
+    /// variant with the ID, or either was deleted.
+    pub async fn update(
+        pool: &PgPool,
+        inventory_id: i64,
+        id: i64,
+        changes: &VariantChanges<'_>,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            UPDATE variants SET
+            sku = COALESCE($3, sku),
+            price = COALESCE($4, price),
+            quantity = COALESCE($5, quantity),
+            size = COALESCE($6, size),
+            weight_grams = COALESCE($7, weight_grams)
+            WHERE id = $2 AND inventory_id = $1 AND deleted_at IS NULL
+            AND inventory_id IN (SELECT id FROM inventory WHERE deleted_at IS NULL)
+            RETURNING *
+            "#,
+        )
+        .bind(inventory_id)
+        .bind(id)
+        .bind(changes.sku)
+        .bind(changes.price)
+        .bind(changes.quantity)
+        .bind(changes.size)
+        .bind(changes.weight_grams)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Hides the variant, like `Inventory::delete`. Returns false if the
+    /// product has no variant with the ID, or either was already deleted.
+    pub async fn delete(pool: &PgPool, inventory_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE variants SET deleted_at = NOW()
+            WHERE id = $2 AND inventory_id = $1 AND deleted_at IS NULL
+            AND inventory_id IN (SELECT id FROM inventory WHERE deleted_at IS NULL)
+            "#,
+        )
+        .bind(inventory_id)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
     }
 }
@@ -1,3 +1,5 @@
 pub mod address;
+pub mod categories;
 pub mod inventory;
 pub mod orders;
+pub mod personal_data;
@@ -6,9 +6,32 @@ use uuid::Uuid;
 
 use crate::{serialize_dt, serialize_uuid, ParseError};
 
+pub const ORDER_STATUSES: [&str; 5] = ["PENDING", "PAID", "SHIPPED", "DELIVERED", "CANCELLED"];
+
+/// Why an order can't be made.
+#[derive(Debug)]
+pub enum Unavailable {
+    /// The address isn't one of the user's, or doesn't exist.
+    Address,
+    /// A variant in the order has been deleted, or never existed.
+    Variant(i64),
+}
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unavailable::Address => write!(f, "address not found"),
+            Unavailable::Variant(id) => write!(f, "variant {} is not available", id),
+        }
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
 #[derive(Deserialize)]
 pub struct OrderRequest {
-    id: i64,
+    #[serde(rename(deserialize = "variantId"))]
+    variant_id: i64,
     quantity: i32,
 }
 
@@ -16,11 +39,12 @@ pub struct OrderRequest {
 pub struct Order {
     #[serde(serialize_with = "serialize_uuid")]
     id: Uuid,
+    /// None once the user has erased their data.
     #[serde(rename(serialize = "userId"))]
-    user_id: i64,
+    user_id: Option<i64>,
     status: String,
     #[serde(rename(serialize = "addressId"))]
-    address_id: i64,
+    address_id: Option<i64>,
     #[serde(serialize_with = "serialize_dt", rename(serialize = "createdAt"))]
     created_at: chrono::DateTime<chrono::Utc>,
     total: i64,
@@ -30,59 +54,108 @@ pub struct Order {
 pub struct OrderDetail {
     #[serde(serialize_with = "serialize_uuid")]
     id: Uuid,
-    name: String,
+    /// The variant and product fields are None for items whose product was
+    /// removed before variants were added.
+    #[serde(rename(serialize = "variantId"))]
+    variant_id: Option<i64>,
+    name: Option<String>,
+    sku: Option<String>,
     #[serde(rename(serialize = "imageUrl"))]
-    image_url: String,
+    image_url: Option<String>,
     status: String,
     quantity: i32,
     total: i32,
 }
 
 impl Order {
+    /// Takes the items out of stock at their current price. Nothing is
+    /// ordered if the address isn't the user's, if a variant or its product
+    /// has been deleted, or if there isn't enough of one in stock, which
+    /// fails the `variants_quantity` check.
     pub async fn new(
         pool: &PgPool,
         user_id: i64,
         address_id: i64,
         request: Vec<OrderRequest>,
-    ) -> Result<Uuid, sqlx::Error> {
-        let mut tx = pool.begin().await?;
+    ) -> Result<Uuid, Either<sqlx::Error, Unavailable>> {
+        let mut tx = pool.begin().await.map_err(Either::Left)?;
         let uuid = Uuid::new_v4();
-        sqlx::query("INSERT INTO orders (id, user_id, address_id) VALUES ($1, $2, $3)")
-            .bind(&uuid)
-            .bind(user_id)
-            .bind(address_id)
-            .execute(&mut tx)
-            // Should rollback according to docs:
-            .await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO orders (id, user_id, address_id)
+            SELECT $1, $2, id FROM address WHERE id = $3 AND user_id = $2
+            "#,
+        )
+        .bind(&uuid)
+        .bind(user_id)
+        .bind(address_id)
+        .execute(&mut tx)
+        // Should rollback according to docs:
+        .await
+        .map_err(Either::Left)?;
+
+        if result.rows_affected() == 0 {
+            return Err(Either::Right(Unavailable::Address));
+        }
+
         for item in request {
-            sqlx::query("INSERT INTO order_items VALUES ($1, $2, $3)")
+            let price: Option<i32> = sqlx::query_scalar(
+                r#"
+                UPDATE variants SET quantity = quantity - $1
+                WHERE id = $2 AND deleted_at IS NULL
+                AND inventory_id IN (SELECT id FROM inventory WHERE deleted_at IS NULL)
+                RETURNING price
+                "#,
+            )
+            .bind(item.quantity)
+            .bind(item.variant_id)
+            .fetch_optional(&mut tx)
+            .await
+            .map_err(Either::Left)?;
+
+            let price = match price {
+                Some(p) => p,
+                None => return Err(Either::Right(Unavailable::Variant(item.variant_id))),
+            };
+
+            sqlx::query("INSERT INTO order_items VALUES ($1, $2, $3, $4)")
                 .bind(&uuid)
-                .bind(item.id)
+                .bind(item.variant_id)
                 .bind(item.quantity)
+                .bind(price)
                 .execute(&mut tx)
                 // Should rollback according to docs:
-                .await?;
-            sqlx::query("UPDATE inventory SET quantity = quantity - $1 WHERE id = $2")
-                .bind(item.quantity)
-                .bind(item.id)
-                .execute(&mut tx)
-                .await?;
+                .await
+                .map_err(Either::Left)?;
         }
 
-        tx.commit().await?;
+        tx.commit().await.map_err(Either::Left)?;
 
         Ok(uuid)
     }
 
+    /// Returns false if there is no order with the ID.
+    pub async fn set_status(pool: &PgPool, id: Uuid, status: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE orders SET status = $1 WHERE id = $2")
+            .bind(status)
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
     pub async fn get(
         pool: &PgPool,
         query: UrlQuery,
     ) -> Result<Vec<Order>, Either<sqlx::Error, ParseError>> {
         let (sql, args) = QueryBuilder::from_str(
             "SELECT orders.id, user_id, status, address_id, \
-            orders.created_at, SUM(order_items.quantity * price) AS total FROM orders \
+            orders.created_at, SUM(order_items.quantity * order_items.unit_price) AS total \
+            FROM orders \
             JOIN order_items ON orders.id = order_items.order_id \
-            JOIN inventory ON order_items.inventory_id = inventory.id",
+            LEFT JOIN variants ON order_items.variant_id = variants.id \
+            LEFT JOIN inventory ON variants.inventory_id = inventory.id",
             query,
         )
         .map_columns([("id", "orders")].into())
@@ -100,6 +173,24 @@ impl Order {
 
         Ok(query.fetch_all(pool).await.map_err(|e| Either::Left(e))?)
     }
+
+    pub async fn from_user_id(pool: &PgPool, user_id: i64) -> Result<Vec<Order>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT orders.id, user_id, status, address_id, orders.created_at,
+            SUM(order_items.quantity * order_items.unit_price) AS total FROM orders
+            JOIN order_items ON orders.id = order_items.order_id
+            LEFT JOIN variants ON order_items.variant_id = variants.id
+            LEFT JOIN inventory ON variants.inventory_id = inventory.id
+            WHERE user_id = $1
+            GROUP BY orders.id
+            ORDER BY orders.created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
 }
 
 impl OrderDetail {
@@ -108,10 +199,11 @@ impl OrderDetail {
         query: UrlQuery,
     ) -> Result<Vec<OrderDetail>, Either<sqlx::Error, ParseError>> {
         let (sql, args) = QueryBuilder::from_str(
-            "SELECT orders.id, name, image_url, status, order_items.quantity, \
-	        price, order_items.quantity * price AS total FROM orders \
+            "SELECT orders.id, variant_id, name, sku, image_url, status, order_items.quantity, \
+            order_items.quantity * order_items.unit_price AS total FROM orders \
             JOIN order_items ON orders.id = order_items.order_id \
-            JOIN inventory ON order_items.inventory_id = inventory.id",
+            LEFT JOIN variants ON order_items.variant_id = variants.id \
+            LEFT JOIN inventory ON variants.inventory_id = inventory.id",
             query,
         )
         .map_columns([("id", "orders"), ("createdAt", "orders")].into())
@@ -129,4 +221,24 @@ impl OrderDetail {
 
         Ok(query.fetch_all(pool).await.map_err(|e| Either::Left(e))?)
     }
+
+    pub async fn from_user_id(
+        pool: &PgPool,
+        user_id: i64,
+    ) -> Result<Vec<OrderDetail>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT orders.id, variant_id, name, sku, image_url, status, order_items.quantity,
+            order_items.quantity * order_items.unit_price AS total FROM orders
+            JOIN order_items ON orders.id = order_items.order_id
+            LEFT JOIN variants ON order_items.variant_id = variants.id
+            LEFT JOIN inventory ON variants.inventory_id = inventory.id
+            WHERE user_id = $1
+            ORDER BY orders.created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
 }
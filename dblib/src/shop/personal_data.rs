@@ -0,0 +1,47 @@
+use serde::Serialize;
+use sqlx::PgPool;
+
+use super::{
+    address::Address,
+    orders::{Order, OrderDetail},
+};
+
+/// Everything the shop database holds about a user, for data exports.
+#[derive(Serialize)]
+pub struct PersonalData {
+    pub addresses: Vec<Address>,
+    pub orders: Vec<Order>,
+    #[serde(rename(serialize = "orderItems"))]
+    pub order_items: Vec<OrderDetail>,
+}
+
+impl PersonalData {
+    pub async fn get(pool: &PgPool, user_id: i64) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            addresses: Address::from_user_id(pool, user_id).await?,
+            orders: Order::from_user_id(pool, user_id).await?,
+            order_items: OrderDetail::from_user_id(pool, user_id).await?,
+        })
+    }
+
+    /// Deletes the user's addresses and takes the user and address off their
+    /// orders. The orders and their items are kept, so totals still add up
+    /// for accounting.
+    pub async fn erase(pool: &PgPool, user_id: i64) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("UPDATE orders SET user_id = NULL, address_id = NULL WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM address WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use serde::Serialize;
+use sqlx::{types::chrono, FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::{serialize_dt, serialize_opt_dt, serialize_uuid};
+
+/// A key for scripts and integrations, only its hash is stored. Keys are
+/// checked by every service's `Auth` layer, so the shop connects to the
+/// users database for them too.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ApiKey {
+    #[serde(serialize_with = "serialize_uuid")]
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// None once the admin who created it has been deleted.
+    #[serde(rename(serialize = "createdBy"))]
+    pub created_by: Option<i64>,
+    #[serde(serialize_with = "serialize_opt_dt", rename(serialize = "expiresAt"))]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(serialize_with = "serialize_opt_dt", rename(serialize = "lastUsedAt"))]
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(serialize_with = "serialize_dt", rename(serialize = "createdAt"))]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ApiKey {
+    /// `expires_at` is in seconds since the unix epoch, keys without one
+    /// don't expire.
+    pub async fn new(
+        pool: &PgPool,
+        name: &str,
+        scopes: &[String],
+        created_by: i64,
+        expires_at: Option<i64>,
+        key_hash: &[u8],
+    ) -> Result<Self, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            INSERT INTO api_keys (
+                id,
+                name,
+                scopes,
+                key_hash,
+                created_by,
+                expires_at
+            ) VALUES ($1, $2, $3, $4, $5, TO_TIMESTAMP($6))
+            RETURNING id, name, scopes, created_by, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(scopes)
+        .bind(key_hash)
+        .bind(created_by)
+        .bind(expires_at)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn list(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT id, name, scopes, created_by, expires_at, last_used_at, created_at
+            FROM api_keys
+            ORDER BY created_at
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn from_hash(pool: &PgPool, key_hash: &[u8]) -> Result<Self, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT id, name, scopes, created_by, expires_at, last_used_at, created_at
+            FROM api_keys
+            WHERE key_hash = $1
+            "#,
+        )
+        .bind(key_hash)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Records that the key was used, unless it already was in the last
+    /// `interval_seconds`, so busy keys aren't written on every request.
+    pub async fn touch(&self, pool: &PgPool, interval_seconds: i64) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now();
+        if matches!(self.last_used_at, Some(t) if (now - t).num_seconds() < interval_seconds) {
+            return Ok(());
+        }
+
+        // Concurrent requests can all get here, only one of them writes:
+        sqlx::query(
+            r#"
+            UPDATE api_keys SET last_used_at = NOW()
+            WHERE id = $1
+            AND (last_used_at IS NULL OR last_used_at < NOW() - $2 * INTERVAL '1 second')
+            "#,
+        )
+        .bind(self.id)
+        .bind(interval_seconds)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the key, it stops working straight away. Returns false if
+    /// there was no such key.
+    pub async fn revoke(pool: &PgPool, id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
@@ -0,0 +1,123 @@
+use convert_case::Case;
+use query::{sql::QueryBuilder, sqlx_bind, UrlQuery};
+use serde::Serialize;
+use sqlx::{types::chrono, Either, FromRow, PgPool};
+
+use crate::{serialize_dt, ParseError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    SignUp,
+    Login,
+    LoginFailed,
+    TokenIssued,
+    PasswordChange,
+    PasswordReset,
+    EmailChange,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::SignUp => "sign_up",
+            Event::Login => "login",
+            Event::LoginFailed => "login_failed",
+            Event::TokenIssued => "token_issued",
+            Event::PasswordChange => "password_change",
+            Event::PasswordReset => "password_reset",
+            Event::EmailChange => "email_change",
+            Event::ApiKeyCreated => "api_key_created",
+            Event::ApiKeyRevoked => "api_key_revoked",
+        }
+    }
+}
+
+/// A security relevant event, for incident response. The table is append
+/// only, updates and deletes do nothing, except for `erase`.
+#[derive(Serialize, FromRow)]
+pub struct AuthEvent {
+    id: i64,
+    #[serde(rename(serialize = "userId"))]
+    user_id: Option<i64>,
+    event: String,
+    /// The email tried for failed logins, which may not have an account.
+    email: Option<String>,
+    ip: Option<String>,
+    #[serde(rename(serialize = "userAgent"))]
+    user_agent: Option<String>,
+    #[serde(serialize_with = "serialize_dt", rename(serialize = "createdAt"))]
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl AuthEvent {
+    pub async fn record(
+        pool: &PgPool,
+        event: Event,
+        user_id: Option<i64>,
+        email: Option<&str>,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO auth_events (user_id, event, email, ip, user_agent)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(user_id)
+        .bind(event.as_str())
+        .bind(email)
+        .bind(ip)
+        .bind(user_agent)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Clears the email, IP and user agent of the user's events, and of
+    /// failed logins with their email. The events themselves are kept.
+    pub async fn erase(pool: &PgPool, user_id: i64, email: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT erase_auth_events($1, $2)")
+            .bind(user_id)
+            .bind(email)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(
+        pool: &PgPool,
+        query: UrlQuery,
+    ) -> Result<Vec<AuthEvent>, Either<sqlx::Error, ParseError>> {
+        let (sql, args) = QueryBuilder::from_str("SELECT * FROM auth_events", query)
+            .convert_case(Case::Snake)
+            .build();
+
+        let mut query = sqlx::query_as(&sql);
+
+        sqlx_bind!(
+            args => query,
+            error: Either::Right(ParseError),
+            "id" => i64,
+            "userId" => i64,
+            "event" => String,
+            "email" => String,
+            "ip" => String,
+            // RFC 3339, eg 2023-01-31T00:00:00Z:
+            "createdAt" => chrono::DateTime<chrono::Utc>
+        );
+
+        query.fetch_all(pool).await.map_err(Either::Left)
+    }
+
+    pub async fn from_user_id(pool: &PgPool, user_id: i64) -> Result<Vec<AuthEvent>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM auth_events WHERE user_id = $1 ORDER BY id")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+}
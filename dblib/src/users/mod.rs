@@ -1 +1,7 @@
+pub mod api_keys;
+pub mod auth_events;
+pub mod password_resets;
+pub mod roles;
+pub mod tokens;
+pub mod totp;
 pub mod users;
@@ -0,0 +1,57 @@
+use sqlx::{PgPool, Row};
+
+/// One-time password reset tokens, only their hash is stored.
+pub struct PasswordReset;
+
+impl PasswordReset {
+    pub async fn create(
+        pool: &PgPool,
+        user_id: i64,
+        token_hash: &[u8],
+        ttl_seconds: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO password_resets (
+                token_hash,
+                user_id,
+                expires_at
+            ) VALUES ($1, $2, NOW() + $3 * INTERVAL '1 second')
+            "#,
+        )
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(ttl_seconds)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the token as used and returns its user, or `None` if it doesn't
+    /// exist, has expired or has already been used.
+    pub async fn consume(pool: &PgPool, token_hash: &[u8]) -> Result<Option<i64>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            UPDATE password_resets SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(pool)
+        .await?;
+
+        row.map(|r| r.try_get("user_id")).transpose()
+    }
+
+    /// Removes any other outstanding tokens for the user.
+    pub async fn clear(pool: &PgPool, user_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use sqlx::{PgPool, Row};
+
+/// Roles granted to a user on top of the default `user` role.
+pub struct Roles;
+
+impl Roles {
+    pub async fn get(pool: &PgPool, user_id: i64) -> Result<Vec<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT role FROM roles WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        rows.iter().map(|r| r.try_get("role")).collect()
+    }
+
+    pub async fn grant(pool: &PgPool, user_id: i64, role: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .bind(role)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke(pool: &PgPool, user_id: i64, role: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM roles WHERE user_id = $1 AND role = $2")
+            .bind(user_id)
+            .bind(role)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use sqlx::{types::chrono, FromRow, PgPool, Row};
+use uuid::Uuid;
+
+/// A refresh token, only its hash is stored. Each use rotates it for a new
+/// one in the same family, so a reused token means the family has leaked.
+#[derive(FromRow)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub user_id: i64,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl RefreshToken {
+    pub async fn new(
+        pool: &PgPool,
+        user_id: i64,
+        family_id: Option<Uuid>,
+        token_hash: &[u8],
+        ttl_seconds: i64,
+    ) -> Result<Self, sqlx::Error> {
+        let id = Uuid::new_v4();
+        // Tokens from a new login start a new family:
+        let family_id = family_id.unwrap_or_else(Uuid::new_v4);
+
+        let row = sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (
+                id,
+                family_id,
+                user_id,
+                token_hash,
+                expires_at
+            ) VALUES ($1, $2, $3, $4, NOW() + $5 * INTERVAL '1 second')
+            RETURNING expires_at
+            "#,
+        )
+        .bind(id)
+        .bind(family_id)
+        .bind(user_id)
+        .bind(token_hash)
+        .bind(ttl_seconds)
+        .fetch_one(pool)
+        .await?;
+
+        let expires_at = row.try_get("expires_at")?;
+
+        Ok(Self {
+            id,
+            family_id,
+            user_id,
+            expires_at,
+            used_at: None,
+            revoked_at: None,
+        })
+    }
+
+    pub async fn from_hash(pool: &PgPool, token_hash: &[u8]) -> Result<Self, sqlx::Error> {
+        let row = sqlx::query_as(
+            r#"
+            SELECT id, family_id, user_id, expires_at, used_at, revoked_at
+            FROM refresh_tokens
+            WHERE token_hash = $1
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    /// Marks the token as used, returns false if it had already been used,
+    /// eg by a concurrent request.
+    pub async fn mark_used(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE refresh_tokens SET used_at = NOW() WHERE id = $1 AND used_at IS NULL",
+        )
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn revoke_family(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+        )
+        .bind(self.family_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_user(pool: &PgPool, user_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
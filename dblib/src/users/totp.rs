@@ -0,0 +1,137 @@
+use sqlx::{PgPool, Row};
+
+/// A user's TOTP secret. 2FA is only on once the secret has been confirmed
+/// with a first code.
+pub struct Totp {
+    pub user_id: i64,
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+}
+
+impl Totp {
+    pub async fn get(pool: &PgPool, user_id: i64) -> Result<Option<Self>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT user_id, secret, confirmed_at IS NOT NULL AS confirmed
+            FROM totp WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let totp = match row {
+            Some(r) => Some(Self {
+                user_id: r.try_get("user_id")?,
+                secret: r.try_get("secret")?,
+                confirmed: r.try_get("confirmed")?,
+            }),
+            None => None,
+        };
+
+        Ok(totp)
+    }
+
+    /// Stores a new unconfirmed secret, replacing any earlier unconfirmed
+    /// one. Returns false if 2FA is already on.
+    pub async fn enroll(pool: &PgPool, user_id: i64, secret: &[u8]) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO totp (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_step = NULL, created_at = NOW()
+            WHERE totp.confirmed_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(secret)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Turns 2FA on, `step` is the time step of the code used to confirm.
+    pub async fn confirm(&self, pool: &PgPool, step: i64) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query(
+            r#"
+            UPDATE totp SET confirmed_at = NOW(), last_step = $2
+            WHERE user_id = $1 AND confirmed_at IS NULL
+            "#,
+        )
+        .bind(self.user_id)
+        .bind(step)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Records the time step of a code that has been used. Returns false if
+    /// that step or a later one has already been used, so each code only
+    /// works once.
+    pub async fn use_step(&self, pool: &PgPool, step: i64) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query(
+            r#"
+            UPDATE totp SET last_step = $2
+            WHERE user_id = $1 AND (last_step IS NULL OR last_step < $2)
+            "#,
+        )
+        .bind(self.user_id)
+        .bind(step)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+/// One-time codes for logging in without the authenticator, only their hash
+/// is stored.
+pub struct RecoveryCodes;
+
+impl RecoveryCodes {
+    /// Replaces the user's recovery codes.
+    pub async fn replace(
+        pool: &PgPool,
+        user_id: i64,
+        code_hashes: &[Vec<u8>],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        for hash in code_hashes {
+            sqlx::query("INSERT INTO recovery_codes (code_hash, user_id) VALUES ($1, $2)")
+                .bind(hash)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Marks the code as used. Returns false if the user has no such unused
+    /// code.
+    pub async fn consume(
+        pool: &PgPool,
+        user_id: i64,
+        code_hash: &[u8],
+    ) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query(
+            r#"
+            UPDATE recovery_codes SET used_at = NOW()
+            WHERE code_hash = $1 AND user_id = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(code_hash)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+}
@@ -1,11 +1,22 @@
 use argon2::{
-    password_hash::{PasswordHasher, SaltString},
-    Argon2,
+    password_hash::{
+        rand_core::OsRng, Error as PasswordError, Output, PasswordHash, PasswordHasher,
+        PasswordVerifier, SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
 };
+use convert_case::Case;
+use query::{sql::QueryBuilder, sqlx_bind, UrlQuery};
 use serde::Serialize;
-use sqlx::{FromRow, PgPool, Row};
+use sqlx::{types::chrono, Either, FromRow, PgPool, Row};
+use std::{env, sync::OnceLock, thread};
+use tokio::sync::Semaphore;
 
-const SALT: &str = "c3VwZXJzZWNyZXRzYWx0";
+use crate::{serialize_dt, serialize_opt_dt, ParseError};
+
+// Passwords used to be hashed with this salt for every account and stored
+// without the PHC prefix. Only kept around to verify (and upgrade) old rows:
+const LEGACY_SALT: &str = "c3VwZXJzZWNyZXRzYWx0";
 
 #[derive(Serialize, FromRow)]
 pub struct User {
@@ -15,6 +26,13 @@ pub struct User {
     #[serde(rename(serialize = "lastName"))]
     last_name: String,
     pub email: String,
+    #[serde(
+        serialize_with = "serialize_opt_dt",
+        rename(serialize = "emailVerifiedAt")
+    )]
+    pub email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(serialize_with = "serialize_dt", rename(serialize = "createdAt"))]
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 impl User {
@@ -33,7 +51,7 @@ impl User {
                 email,
                 password
             ) VALUES ($1, $2, $3, $4)
-            RETURNING id 
+            RETURNING id, created_at
             "#,
         )
         .bind(&first_name)
@@ -43,39 +61,143 @@ impl User {
         .fetch_one(pool)
         .await?;
 
-        let id = row.try_get("id")?;
-
         Ok(User {
-            id,
+            id: row.try_get("id")?,
             first_name,
             last_name,
             email,
+            email_verified_at: None,
+            created_at: row.try_get("created_at")?,
         })
     }
 
-    pub async fn from_email_and_password(
+    /// Returns the user along with their stored password hash, so it can be
+    /// checked with `Password::verify`.
+    pub async fn from_email_with_password(
         pool: &PgPool,
-        email: String,
-        password: &[u8],
-    ) -> Result<User, sqlx::Error> {
-        let row = sqlx::query_as(
+        email: &str,
+    ) -> Result<(User, Vec<u8>), sqlx::Error> {
+        let row = sqlx::query(
             r#"
-            SELECT id, first_name, last_name, email FROM users
-            WHERE email = $1 AND password = $2
+            SELECT id, first_name, last_name, email, email_verified_at, created_at, password FROM users
+            WHERE email = $1
             "#,
         )
         .bind(email)
-        .bind(password)
         .fetch_one(pool)
         .await?;
 
-        Ok(row)
+        let user = User::from_row(&row)?;
+        let password = row.try_get("password")?;
+
+        Ok((user, password))
+    }
+
+    pub async fn from_id_with_password(
+        pool: &PgPool,
+        id: i64,
+    ) -> Result<(User, Vec<u8>), sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, first_name, last_name, email, email_verified_at, created_at, password FROM users
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        let user = User::from_row(&row)?;
+        let password = row.try_get("password")?;
+
+        Ok((user, password))
+    }
+
+    /// Only changes the fields that are `Some`, all in one statement so a
+    /// taken email leaves the rest unchanged. Changing the email means it has
+    /// to be verified again. `password` is the hash.
+    pub async fn update(
+        &mut self,
+        pool: &PgPool,
+        first_name: Option<String>,
+        last_name: Option<String>,
+        email: Option<String>,
+        password: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET
+                first_name = COALESCE($1, first_name),
+                last_name = COALESCE($2, last_name),
+                email = COALESCE($3, email),
+                email_verified_at = CASE WHEN $3 IS NULL THEN email_verified_at END,
+                password = COALESCE($4, password)
+            WHERE id = $5
+            "#,
+        )
+        .bind(&first_name)
+        .bind(&last_name)
+        .bind(&email)
+        .bind(password.map(String::into_bytes))
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        if let Some(first_name) = first_name {
+            self.first_name = first_name;
+        }
+        if let Some(last_name) = last_name {
+            self.last_name = last_name;
+        }
+        if let Some(email) = email {
+            self.email = email;
+            self.email_verified_at = None;
+        }
+
+        Ok(())
     }
 
-    pub async fn from_id(pool: &PgPool, id: uuid::Uuid) -> Result<User, sqlx::Error> {
+    pub async fn set_password(&self, pool: &PgPool, password: String) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+            .bind(password.into_bytes())
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Only verifies if the email hasn't changed since the link was sent,
+    /// returns false if it has.
+    pub async fn set_email_verified(
+        &self,
+        pool: &PgPool,
+        email: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("UPDATE users SET email_verified_at = NOW() WHERE id = $1 AND email = $2")
+                .bind(self.id)
+                .bind(email)
+                .execute(pool)
+                .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Tokens, roles and password resets are deleted with the user.
+    pub async fn delete(self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn from_id(pool: &PgPool, id: i64) -> Result<User, sqlx::Error> {
         let row = sqlx::query_as(
             r#"
-            SELECT id, first_name, last_name, email FROM users
+            SELECT id, first_name, last_name, email, email_verified_at, created_at FROM users
             WHERE id = $1
             "#,
         )
@@ -89,7 +211,7 @@ impl User {
     pub async fn from_email(pool: &PgPool, email: &str) -> Result<User, sqlx::Error> {
         let row = sqlx::query_as(
             r#"
-            SELECT id, first_name, last_name, email FROM users
+            SELECT id, first_name, last_name, email, email_verified_at, created_at FROM users
             WHERE email = $1
             "#,
         )
@@ -99,20 +221,340 @@ impl User {
 
         Ok(row)
     }
+
+    /// For admins to look up users, filtering on `id`, `email`, `firstName`,
+    /// `lastName` and `createdAt`.
+    pub async fn get(
+        pool: &PgPool,
+        query: UrlQuery,
+    ) -> Result<Vec<User>, Either<sqlx::Error, ParseError>> {
+        let (sql, args) = QueryBuilder::from_str(
+            "SELECT id, first_name, last_name, email, email_verified_at, created_at FROM users",
+            query,
+        )
+        .convert_case(Case::Snake)
+        .build();
+
+        let mut query = sqlx::query_as(&sql);
+
+        sqlx_bind!(
+            args => query,
+            error: Either::Right(ParseError),
+            "id" => i64,
+            "email" => String,
+            "firstName" => String,
+            "lastName" => String,
+            // RFC 3339, eg 2023-01-31T00:00:00Z:
+            "createdAt" => chrono::DateTime<chrono::Utc>
+        );
+
+        query.fetch_all(pool).await.map_err(Either::Left)
+    }
+}
+
+static PARAMS: OnceLock<Params> = OnceLock::new();
+static HASHING: OnceLock<Semaphore> = OnceLock::new();
+
+/// A `PASSWORD_*` env var that isn't a number, or Argon2 settings that are
+/// out of range.
+#[derive(Debug)]
+pub enum PasswordConfigError {
+    NotANumber(&'static str),
+    Params(argon2::Error),
+}
+
+impl std::fmt::Display for PasswordConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordConfigError::NotANumber(key) => write!(f, "{} must be a number", key),
+            PasswordConfigError::Params(e) => write!(
+                f,
+                "PASSWORD_MEMORY_KIB, PASSWORD_ITERATIONS or PASSWORD_PARALLELISM: {}",
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PasswordConfigError {}
+
+fn env_number<T: std::str::FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &'static str,
+) -> Result<Option<T>, PasswordConfigError> {
+    match var(key) {
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| PasswordConfigError::NotANumber(key)),
+        None => Ok(None),
+    }
+}
+
+/// Argon2 settings from `PASSWORD_MEMORY_KIB`, `PASSWORD_ITERATIONS` and
+/// `PASSWORD_PARALLELISM`, looked up with `var`. The defaults are argon2's.
+fn params_from_env(var: impl Fn(&str) -> Option<String>) -> Result<Params, PasswordConfigError> {
+    Params::new(
+        env_number(&var, "PASSWORD_MEMORY_KIB")?.unwrap_or(Params::DEFAULT_M_COST),
+        env_number(&var, "PASSWORD_ITERATIONS")?.unwrap_or(Params::DEFAULT_T_COST),
+        env_number(&var, "PASSWORD_PARALLELISM")?.unwrap_or(Params::DEFAULT_P_COST),
+        None,
+    )
+    .map_err(PasswordConfigError::Params)
+}
+
+/// How many hashes can run at once, from `PASSWORD_HASH_CONCURRENCY` looked
+/// up with `var`, or the number of CPUs.
+fn concurrency_from_env(
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<usize, PasswordConfigError> {
+    let permits = env_number(&var, "PASSWORD_HASH_CONCURRENCY")?
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+
+    Ok(permits.max(1))
+}
+
+/// Reads the password hashing settings from the env. Services that hash
+/// passwords should call this at startup, so bad settings stop them there
+/// rather than failing requests. Only the first call has any effect.
+pub fn init_password_hashing() -> Result<(), PasswordConfigError> {
+    let params = params_from_env(|key| env::var(key).ok())?;
+    let permits = concurrency_from_env(|key| env::var(key).ok())?;
+
+    let _ = PARAMS.set(params);
+    let _ = HASHING.set(Semaphore::new(permits));
+
+    Ok(())
+}
+
+/// Argon2 settings for new hashes, see `init_password_hashing`. argon2's
+/// defaults if it wasn't called, eg in tests. Hashes made with other
+/// settings still verify, and are replaced at the next login.
+pub fn password_params() -> &'static Params {
+    PARAMS.get_or_init(Params::default)
+}
+
+/// Bounds the hashes running at once, see `init_password_hashing`. Each one
+/// holds a blocking thread and `PASSWORD_MEMORY_KIB` of memory, so a burst
+/// of logins queues here instead.
+fn hashing() -> &'static Semaphore {
+    HASHING.get_or_init(|| Semaphore::new(thread::available_parallelism().map_or(1, |n| n.get())))
+}
+
+/// Runs `f` on tokio's blocking threads, so hashing doesn't hold up the
+/// executor.
+async fn run_blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let _permit = hashing()
+        .acquire()
+        .await
+        .expect("hashing semaphore is never closed");
+
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("password hashing panicked")
+}
+
+/// A hash of a random password made with `password_params`, for
+/// `verify_dummy_async`.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+
+    DUMMY.get_or_init(|| {
+        let password = SaltString::generate(&mut OsRng);
+        Password::hash(password.as_str()).expect("hashing a random password failed")
+    })
 }
 
 pub struct Password;
 
 impl Password {
-    pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
-        let salt = SaltString::new(SALT)?;
+    /// Hashes with a fresh random salt, returning a PHC string that carries
+    /// the salt and parameters with it. This blocks, handlers should use
+    /// `hash_async`.
+    pub fn hash(password: &str) -> Result<String, PasswordError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            password_params().clone(),
+        );
+        let hash = argon2.hash_password(password.as_bytes(), &salt)?;
+
+        Ok(hash.to_string())
+    }
+
+    pub async fn hash_async(password: String) -> Result<String, PasswordError> {
+        run_blocking(move || Self::hash(&password)).await
+    }
+
+    pub async fn verify_async(password: String, stored: Vec<u8>) -> Result<(), PasswordError> {
+        run_blocking(move || Self::verify(&password, &stored)).await
+    }
+
+    /// Does the same work as `verify_async` against a hash nothing matches,
+    /// so logins with an unknown email take as long as a wrong password.
+    pub async fn verify_dummy_async(password: String) {
+        run_blocking(move || {
+            let _ = Self::verify(&password, dummy_hash().as_bytes());
+        })
+        .await
+    }
+
+    pub fn verify(password: &str, stored: &[u8]) -> Result<(), PasswordError> {
+        let stored = std::str::from_utf8(stored).map_err(|_| PasswordError::PhcStringInvalid)?;
+
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash),
+            Err(_) => Self::verify_legacy(password, stored),
+        }
+    }
+
+    /// True if the stored hash is in the old shared salt format, or was made
+    /// with another algorithm, version or settings than `Password::hash`
+    /// uses, and should be replaced once the password is known.
+    pub fn needs_rehash(stored: &[u8]) -> bool {
+        let hash = match std::str::from_utf8(stored).map(PasswordHash::new) {
+            Ok(Ok(h)) => h,
+            _ => return true,
+        };
+
+        // A hash without a version is 0x10:
+        if hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+
+        let current = password_params();
+        match Params::try_from(&hash) {
+            Ok(p) => {
+                p.m_cost() != current.m_cost()
+                    || p.t_cost() != current.t_cost()
+                    || p.p_cost() != current.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+
+    fn verify_legacy(password: &str, stored: &str) -> Result<(), PasswordError> {
+        let salt = SaltString::new(LEGACY_SALT)?;
         let argon2 = Argon2::default();
-        let hash = argon2.hash_password(password.as_bytes(), &salt).unwrap();
+        let hash = argon2.hash_password(password.as_bytes(), &salt)?;
+
+        let expected = Output::b64_decode(stored)?;
+        // Output comparison is constant time:
+        match hash.hash {
+            Some(output) if output == expected => Ok(()),
+            _ => Err(PasswordError::Password),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use argon2::{
+        password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+        Algorithm, Argon2, Params, Version,
+    };
+
+    use super::{
+        concurrency_from_env, dummy_hash, params_from_env, password_params, Password,
+        PasswordConfigError,
+    };
 
-        Ok(hash.hash.unwrap().to_string())
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = Password::hash("password123").unwrap();
+
+        assert!(Password::verify("password123", hash.as_bytes()).is_ok());
+        assert!(Password::verify("password321", hash.as_bytes()).is_err());
+        assert!(!Password::needs_rehash(hash.as_bytes()));
+
+        // Salts are per hash:
+        assert_ne!(hash, Password::hash("password123").unwrap());
+
+        // Hashes with other settings still verify, but are replaced:
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let other = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"password123", &salt)
+            .unwrap()
+            .to_string();
+        assert!(Password::verify("password123", other.as_bytes()).is_ok());
+        assert!(Password::needs_rehash(other.as_bytes()));
+
+        // So are hashes with the same settings but another algorithm or
+        // version:
+        for (algorithm, version) in [
+            (Algorithm::Argon2i, Version::V0x13),
+            (Algorithm::Argon2d, Version::V0x13),
+            (Algorithm::Argon2id, Version::V0x10),
+        ] {
+            let other = Argon2::new(algorithm, version, password_params().clone())
+                .hash_password(b"password123", &salt)
+                .unwrap()
+                .to_string();
+            assert!(Password::verify("password123", other.as_bytes()).is_ok());
+            assert!(Password::needs_rehash(other.as_bytes()));
+        }
+
+        // Unknown emails are checked against a hash as slow as real ones:
+        assert!(!Password::needs_rehash(dummy_hash().as_bytes()));
+        assert!(Password::verify("password123", dummy_hash().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let unset = |_: &str| None;
+        assert_eq!(
+            params_from_env(unset).unwrap().m_cost(),
+            Params::DEFAULT_M_COST
+        );
+
+        // Both are errors rather than falling back to the defaults:
+        let iterations = |value: &'static str| {
+            move |key: &str| (key == "PASSWORD_ITERATIONS").then(|| value.to_string())
+        };
+        assert!(matches!(
+            params_from_env(iterations("three")),
+            Err(PasswordConfigError::NotANumber("PASSWORD_ITERATIONS"))
+        ));
+        assert!(matches!(
+            params_from_env(iterations("0")),
+            Err(PasswordConfigError::Params(_))
+        ));
+        assert_eq!(params_from_env(iterations("5")).unwrap().t_cost(), 5);
+
+        let concurrency = |value: &'static str| {
+            move |key: &str| (key == "PASSWORD_HASH_CONCURRENCY").then(|| value.to_string())
+        };
+        assert!(concurrency_from_env(concurrency("-1")).is_err());
+        assert_eq!(concurrency_from_env(concurrency("0")).unwrap(), 1);
+        assert_eq!(concurrency_from_env(concurrency("4")).unwrap(), 4);
+        assert!(concurrency_from_env(unset).unwrap() >= 1);
     }
 
-    // pub fn verify(password: &[u8], stored: &[u8]) -> Result<(), argon2::password_hash::Error> {
-    //     argon2.verify_password(password, &stored)
-    // }
+    #[tokio::test]
+    async fn test_hash_async() {
+        let hash = Password::hash_async("password123".into()).await.unwrap();
+
+        assert!(
+            Password::verify_async("password123".into(), hash.into_bytes())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_verify_legacy() {
+        let stored = b"g82ABDanMjndmcgXPOPiZSkEPn3CqDIDTF79kzFn6eU";
+
+        assert!(Password::verify("password", stored).is_ok());
+        assert!(Password::verify("wrong", stored).is_err());
+        assert!(Password::needs_rehash(stored));
+    }
 }
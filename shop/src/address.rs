@@ -1,14 +1,23 @@
+use apilib::{
+    scope_query,
+    validate::{validate, Validate, Validator},
+};
 use dblib::shop::address::Address;
-use hyper::{Body, Response, StatusCode};
+use hyper::{http::Extensions, Body, Response, StatusCode};
 use query::UrlQuery;
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::{Either, PgPool};
+use towerlib::auth::get_claims;
+
+/// The size of the `address` columns.
+const MAX_LENGTH: usize = 100;
+
+/// Longer than any country's postcodes.
+const MAX_POSTCODE_LENGTH: usize = 16;
 
 #[derive(Deserialize)]
 struct PostAddressRequest {
-    #[serde(rename(deserialize = "userId"))]
-    user_id: i64,
     #[serde(rename(deserialize = "firstName"))]
     first_name: String,
     #[serde(rename(deserialize = "lastName"))]
@@ -21,11 +30,36 @@ struct PostAddressRequest {
     city: String,
 }
 
+impl Validate for PostAddressRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("firstName", &self.first_name).max_length(
+            "firstName",
+            &self.first_name,
+            MAX_LENGTH,
+        );
+        v.required("lastName", &self.last_name)
+            .max_length("lastName", &self.last_name, MAX_LENGTH);
+        v.required("address1", &self.address_1)
+            .max_length("address1", &self.address_1, MAX_LENGTH);
+        v.max_length("address2", &self.address_2, MAX_LENGTH);
+        v.required("postcode", &self.postcode).max_length(
+            "postcode",
+            &self.postcode,
+            MAX_POSTCODE_LENGTH,
+        );
+        v.required("city", &self.city)
+            .max_length("city", &self.city, MAX_LENGTH);
+    }
+}
+
 pub async fn post_address(
     pool: &PgPool,
+    extensions: &Extensions,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let claims = get_claims(extensions)?;
+
     let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::debug!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -36,9 +70,11 @@ pub async fn post_address(
         (StatusCode::UNPROCESSABLE_ENTITY, None)
     })?;
 
+    validate(&r)?;
+
     let address = Address::new(
         pool,
-        r.user_id,
+        claims.id,
         r.first_name,
         r.last_name,
         r.address_1,
@@ -65,11 +101,15 @@ pub async fn post_address(
 
 pub async fn get_address(
     pool: &PgPool,
+    extensions: &Extensions,
     query: Option<&str>,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let query = query.unwrap_or("");
-    let parsed = UrlQuery::new(query, ["userId"]).map_err(|e| {
+    let claims = get_claims(extensions)?;
+
+    // Only the user's own addresses:
+    let query = scope_query(query, "userId", &claims.id.to_string());
+    let parsed = UrlQuery::new(&query, ["userId"]).map_err(|e| {
         log::debug!("{:?}", e);
         (
             StatusCode::BAD_REQUEST,
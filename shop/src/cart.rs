@@ -1,19 +1,130 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
-use hyper::{Body, HeaderMap, Response, StatusCode};
-use redis::{AsyncCommands, Client as RedisClient};
+use apilib::validate::{validate, Validate, Validator};
+use hyper::{http::Extensions, Body, HeaderMap, Response, StatusCode};
+use redis::{aio::Connection, AsyncCommands, Client as RedisClient};
 use serde::{Deserialize, Serialize};
-use towerlib::session::get_session;
+use towerlib::{
+    auth::Claims,
+    cart::{add_user_session, cart_key, user_cart_key},
+    session::{get_session, save_session},
+};
+
+use crate::inventory::{MAX_IMAGE_URL_LENGTH, MAX_NAME_LENGTH};
+
+/// Logged in users have a cart of their own, so it follows them across
+/// sessions. The first time a session is seen with the user, it is stored
+/// and its guest cart is merged into theirs. Guests' sessions are stored when
+/// `adding` to the cart, until then they are only an ID.
+async fn get_cart_key(
+    con: &mut Connection,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    adding: bool,
+) -> Result<String, (StatusCode, Option<serde_json::Value>)> {
+    let session = get_session(headers)?;
+
+    let claims = match extensions.get::<Claims>() {
+        Some(c) => c,
+        None => {
+            if adding {
+                save_session(con, session).await.map_err(|e| {
+                    log::error!("{}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, None)
+                })?;
+            }
+            return Ok(cart_key(session));
+        }
+    };
+
+    let key = user_cart_key(claims.id);
+
+    // So the session's guest cart can be erased with the user's data:
+    let added = add_user_session(con, claims.id, session)
+        .await
+        .map_err(|e| {
+            log::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        })?;
+
+    if added {
+        // Otherwise the session is replaced on every request, and is never
+        // seen again:
+        save_session(con, session).await.map_err(|e| {
+            log::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        })?;
+
+        merge_carts(con, &cart_key(session), &key)
+            .await
+            .map_err(|e| {
+                log::error!("{}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, None)
+            })?;
+    }
+
+    Ok(key)
+}
 
-fn cart_key(session_id: &str) -> String {
-    let mut key = String::from("cart:");
-    key.push_str(session_id);
-    key
+/// Moves the items in `from` to `to`, adding the quantities of items that
+/// are in both, up to `MAX_QUANTITY`. `from` is removed. Both carts are
+/// watched, so if another request changes either one the merge starts again,
+/// and nothing is lost or merged twice.
+async fn merge_carts(con: &mut Connection, from: &str, to: &str) -> redis::RedisResult<()> {
+    loop {
+        redis::cmd("WATCH")
+            .arg(from)
+            .arg(to)
+            .query_async::<_, ()>(con)
+            .await?;
+
+        let guest: Vec<String> = con.smembers(from).await?;
+        if guest.is_empty() {
+            return redis::cmd("UNWATCH").query_async(con).await;
+        }
+
+        let user: Vec<String> = con.smembers(to).await?;
+
+        let mut merged: BTreeMap<i64, CartItem> = BTreeMap::new();
+        for item in user.iter().chain(guest.iter()) {
+            let item: CartItem = match serde_json::from_str(item) {
+                Ok(i) => i,
+                Err(e) => {
+                    log::error!("{}", e);
+                    continue;
+                }
+            };
+
+            match merged.get_mut(&item.variant_id) {
+                Some(existing) => {
+                    existing.quantity = (existing.quantity + item.quantity).min(MAX_QUANTITY as i32)
+                }
+                None => {
+                    merged.insert(item.variant_id, item);
+                }
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(from).ignore().del(to).ignore();
+        for item in merged.values() {
+            pipe.sadd(to, serde_json::to_string(item).unwrap()).ignore();
+        }
+
+        // Nil if a watched key changed:
+        let done: Option<()> = pipe.query_async(con).await?;
+        if done.is_some() {
+            return Ok(());
+        }
+    }
 }
 
+const MAX_QUANTITY: i64 = 100;
+
 #[derive(Serialize, Deserialize)]
 struct CartItem {
-    id: i64,
+    #[serde(rename(deserialize = "variantId", serialize = "variantId"))]
+    variant_id: i64,
     name: String,
     price: i32,
     #[serde(rename(deserialize = "imageUrl", serialize = "imageUrl"))]
@@ -21,19 +132,29 @@ struct CartItem {
     quantity: i32,
 }
 
+impl Validate for CartItem {
+    fn validate(&self, v: &mut Validator) {
+        v.positive("variantId", self.variant_id);
+        v.required("name", &self.name)
+            .max_length("name", &self.name, MAX_NAME_LENGTH);
+        v.range("price", self.price.into(), 0, i32::MAX.into());
+        v.max_length("imageUrl", &self.image_url, MAX_IMAGE_URL_LENGTH);
+        v.range("quantity", self.quantity.into(), 1, MAX_QUANTITY);
+    }
+}
+
 // To share with post_orders:
 pub async fn get_cart_hashset(
     redis: &RedisClient,
     headers: &HeaderMap,
+    extensions: &Extensions,
 ) -> Result<HashSet<String>, (StatusCode, Option<serde_json::Value>)> {
-    let session = get_session(&headers)?;
-
     let mut con = redis.get_async_connection().await.map_err(|e| {
         log::error!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
-    let key = cart_key(session);
+    let key = get_cart_key(&mut con, headers, extensions, false).await?;
 
     let cart: HashSet<String> = con.smembers(key).await.map_err(|e| {
         log::error!("{}", e);
@@ -46,9 +167,10 @@ pub async fn get_cart_hashset(
 pub async fn get_cart(
     redis: &RedisClient,
     headers: &HeaderMap,
+    extensions: &Extensions,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let cart = get_cart_hashset(redis, headers).await?;
+    let cart = get_cart_hashset(redis, headers, extensions).await?;
 
     let mut res = Vec::new();
 
@@ -74,11 +196,10 @@ pub async fn get_cart(
 pub async fn post_cart(
     redis: &RedisClient,
     headers: &HeaderMap,
+    extensions: &Extensions,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let session = get_session(&headers)?;
-
     let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::error!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -90,6 +211,8 @@ pub async fn post_cart(
         (StatusCode::UNPROCESSABLE_ENTITY, None)
     })?;
 
+    validate(&json)?;
+
     let body_str = serde_json::to_string(&json).unwrap();
 
     let mut con = redis.get_async_connection().await.map_err(|e| {
@@ -97,7 +220,7 @@ pub async fn post_cart(
         (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
-    let key = cart_key(session);
+    let key = get_cart_key(&mut con, headers, extensions, true).await?;
 
     con.sadd(key, body_str).await.map_err(|e| {
         log::error!("{}", e);
@@ -116,14 +239,19 @@ struct PatchCartRequest {
     new: CartItem,
 }
 
+impl Validate for PatchCartRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.nested("old", &self.old).nested("new", &self.new);
+    }
+}
+
 pub async fn patch_cart(
     redis: &RedisClient,
     headers: &HeaderMap,
+    extensions: &Extensions,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let session = get_session(&headers)?;
-
     let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::error!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -134,6 +262,8 @@ pub async fn patch_cart(
         (StatusCode::UNPROCESSABLE_ENTITY, None)
     })?;
 
+    validate(&r)?;
+
     let old = serde_json::to_string(&r.old).map_err(|e| {
         log::error!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -149,7 +279,7 @@ pub async fn patch_cart(
         (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
-    let key = cart_key(session);
+    let key = get_cart_key(&mut con, headers, extensions, true).await?;
 
     // TODO: redis::transaction doesn't take async connection?
     con.srem(&key, old).await.map_err(|e| {
@@ -171,11 +301,10 @@ pub async fn patch_cart(
 pub async fn delete_cart(
     redis: &RedisClient,
     headers: &HeaderMap,
+    extensions: &Extensions,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let session = get_session(&headers)?;
-
     let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::error!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -186,6 +315,8 @@ pub async fn delete_cart(
         (StatusCode::UNPROCESSABLE_ENTITY, None)
     })?;
 
+    validate(&json)?;
+
     let body_str = serde_json::to_string(&json).unwrap();
 
     let mut con = redis.get_async_connection().await.map_err(|e| {
@@ -193,7 +324,7 @@ pub async fn delete_cart(
         (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
-    let key = cart_key(session);
+    let key = get_cart_key(&mut con, headers, extensions, false).await?;
 
     con.srem(&key, body_str).await.map_err(|e| {
         log::error!("{}", e);
@@ -205,3 +336,171 @@ pub async fn delete_cart(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::Extensions, HeaderMap};
+    use redis::{AsyncCommands, Client as RedisClient};
+    use serde_json::json;
+    use towerlib::{
+        auth::{Claims, Role},
+        session::SESSION_ID,
+    };
+    use uuid::Uuid;
+
+    use super::{cart_key, get_cart_key, merge_carts, user_cart_key, CartItem, MAX_QUANTITY};
+    use crate::rand_user_id;
+
+    fn item(variant_id: i64, quantity: i32) -> String {
+        json!({ "variantId": variant_id, "name": "Teapot", "price": 1000, "imageUrl": "", "quantity": quantity })
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_merge_carts() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let mut con = redis.get_async_connection().await.unwrap();
+
+        let guest = cart_key(&Uuid::new_v4().to_string());
+        let user = user_cart_key(rand_user_id());
+
+        con.sadd::<_, _, ()>(&guest, &[item(1, 2), item(2, 1)])
+            .await
+            .unwrap();
+        con.sadd::<_, _, ()>(&user, &[item(1, 3), item(3, 1)])
+            .await
+            .unwrap();
+
+        merge_carts(&mut con, &guest, &user).await.unwrap();
+
+        let exists: bool = con.exists(&guest).await.unwrap();
+        assert!(!exists);
+
+        let merged: Vec<String> = con.smembers(&user).await.unwrap();
+        let mut merged: Vec<(i64, i32)> = merged
+            .iter()
+            .map(|i| serde_json::from_str::<CartItem>(i).unwrap())
+            .map(|i| (i.variant_id, i.quantity))
+            .collect();
+        merged.sort();
+        assert_eq!(merged, vec![(1, 5), (2, 1), (3, 1)]);
+
+        // Nothing left to merge the second time:
+        merge_carts(&mut con, &guest, &user).await.unwrap();
+        let merged: Vec<String> = con.smembers(&user).await.unwrap();
+        assert_eq!(merged.len(), 3);
+
+        con.del::<_, ()>(&user).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_merge_carts_max_quantity() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let mut con = redis.get_async_connection().await.unwrap();
+
+        let guest = cart_key(&Uuid::new_v4().to_string());
+        let user = user_cart_key(rand_user_id());
+
+        con.sadd::<_, _, ()>(&guest, item(1, 60)).await.unwrap();
+        con.sadd::<_, _, ()>(&user, item(1, 60)).await.unwrap();
+
+        merge_carts(&mut con, &guest, &user).await.unwrap();
+
+        let merged: Vec<String> = con.smembers(&user).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<CartItem>(&merged[0])
+                .unwrap()
+                .quantity as i64,
+            MAX_QUANTITY
+        );
+
+        con.del::<_, ()>(&user).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_cart_key_merges_once() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let mut con = redis.get_async_connection().await.unwrap();
+
+        let session_id = Uuid::new_v4().simple().to_string();
+        let mut headers = HeaderMap::new();
+        headers.insert(SESSION_ID, session_id.parse().unwrap());
+
+        let claims = Claims::new(
+            rand_user_id(),
+            "bob@smith.com".to_string(),
+            Role::User,
+            true,
+        );
+        let id = claims.id;
+        let mut extensions = Extensions::new();
+        extensions.insert(claims);
+
+        let guest = cart_key(&session_id);
+        con.sadd::<_, _, ()>(&guest, item(1, 1)).await.unwrap();
+
+        let key = get_cart_key(&mut con, &headers, &extensions, false)
+            .await
+            .unwrap();
+        assert_eq!(key, user_cart_key(id));
+
+        // The session is kept, so the same ID is sent next time:
+        let exists: bool = con.exists(format!("session:{}", session_id)).await.unwrap();
+        assert!(exists);
+
+        let ttl: i64 = con
+            .ttl(format!("{}:sessions", user_cart_key(id)))
+            .await
+            .unwrap();
+        assert!(ttl > 0);
+
+        // The guest cart is only merged the first time:
+        con.sadd::<_, _, ()>(&guest, item(1, 1)).await.unwrap();
+        get_cart_key(&mut con, &headers, &extensions, false)
+            .await
+            .unwrap();
+
+        let user: Vec<String> = con.smembers(&key).await.unwrap();
+        assert_eq!(user.len(), 1);
+        let exists: bool = con.exists(&guest).await.unwrap();
+        assert!(exists);
+
+        con.del::<_, ()>(&[guest, key, format!("session:{}", session_id)])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_merge_carts_concurrently() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let user = user_cart_key(rand_user_id());
+
+        // Several sessions logging in at once, each with the same item:
+        let mut merges = Vec::new();
+        for _ in 0..20 {
+            let mut con = redis.get_async_connection().await.unwrap();
+            let guest = cart_key(&Uuid::new_v4().to_string());
+            con.sadd::<_, _, ()>(&guest, item(1, 1)).await.unwrap();
+
+            let user = user.clone();
+            merges.push(tokio::spawn(async move {
+                merge_carts(&mut con, &guest, &user).await.unwrap();
+            }));
+        }
+        for merge in merges {
+            merge.await.unwrap();
+        }
+
+        let mut con = redis.get_async_connection().await.unwrap();
+        let merged: Vec<String> = con.smembers(&user).await.unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<CartItem>(&merged[0])
+                .unwrap()
+                .quantity,
+            20
+        );
+
+        con.del::<_, ()>(&user).await.unwrap();
+    }
+}
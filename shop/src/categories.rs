@@ -0,0 +1,49 @@
+use dblib::shop::categories::Category;
+use hyper::{Body, Response, StatusCode};
+use sqlx::PgPool;
+
+pub async fn get_categories(
+    pool: &PgPool,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let categories = Category::tree(pool).await.map_err(|e| {
+        log::error!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    let res = serde_json::to_string(&categories).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Response, StatusCode};
+
+    use super::get_categories;
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_get_categories(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let res = get_categories(&pool, Response::<Body>::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let tree: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(tree[0]["slug"], "tea");
+        let black_tea = &tree[0]["children"][0];
+        assert_eq!(black_tea["slug"], "black-tea");
+        assert_eq!(black_tea["children"][0]["slug"], "earl-grey");
+        assert_eq!(black_tea["children"][1]["slug"], "english-breakfast");
+
+        Ok(())
+    }
+}
@@ -1,16 +1,327 @@
-use dblib::shop::inventory::Inventory;
+use apilib::validate::{validate, Validate, Validator};
+use dblib::{
+    shop::inventory::{Facets, Filter, Inventory, NewVariant, Product, Variant, VariantChanges},
+    ParseError,
+};
 use hyper::{Body, Response, StatusCode};
 use query::UrlQuery;
+use serde::{Deserialize, Deserializer};
 use serde_json::json;
 use sqlx::{Either, PgPool};
 
+/// The sizes of the `inventory` columns, cart items copy them too.
+pub(crate) const MAX_NAME_LENGTH: usize = 100;
+pub(crate) const MAX_IMAGE_URL_LENGTH: usize = 255;
+
+/// The sizes of the `variants` columns.
+const MAX_SKU_LENGTH: usize = 64;
+const MAX_SIZE_LENGTH: usize = 50;
+
+const MAX_VARIANTS: usize = 50;
+
+/// Descriptions are `TEXT`, this keeps them to a sensible size.
+const MAX_DESCRIPTION_LENGTH: usize = 5000;
+
+const MAX_SEARCH_LENGTH: usize = 100;
+
+/// The sizes of the `categories` and `tags` columns.
+const MAX_SLUG_LENGTH: usize = 100;
+const MAX_TAG_LENGTH: usize = 50;
+
+const MAX_TAGS: usize = 10;
+
+/// Takes the `keys` params out of the query string, decoded and with empty
+/// ones dropped, leaving the rest for `UrlQuery`.
+fn split_params(query: &str, keys: &[&str]) -> (Vec<(String, String)>, String) {
+    let mut params = Vec::new();
+
+    let rest: Vec<&str> = query
+        .split("&")
+        .filter(|q| match form_urlencoded::parse(q.as_bytes()).next() {
+            Some((k, v)) if keys.contains(&k.as_ref()) => {
+                if !v.trim().is_empty() {
+                    params.push((k.into_owned(), v.trim().to_owned()));
+                }
+                false
+            }
+            _ => true,
+        })
+        .collect();
+
+    (params, rest.join("&"))
+}
+
+/// Search results are sorted best matches first, unless the query sorts
+/// them.
+fn sort_by_rank(query: String) -> String {
+    let sorted = query
+        .split("&")
+        .any(|q| q.split_once("=").map_or(q, |(k, _)| k) == "sort");
+
+    match (sorted, query.is_empty()) {
+        (true, _) => query,
+        (false, true) => "sort=rank-desc".into(),
+        (false, false) => format!("{}&sort=rank-desc", query),
+    }
+}
+
+/// The `search`, `category` and `tag` params, `tag` can be given more than
+/// once.
+fn get_filter(
+    params: &[(String, String)],
+) -> Result<Filter, (StatusCode, Option<serde_json::Value>)> {
+    let mut filter = Filter::default();
+
+    for (k, v) in params {
+        match k.as_str() {
+            "search" => filter.search = Some(v.clone()),
+            "category" => filter.category = Some(v.clone()),
+            "tag" if !filter.tags.contains(v) => filter.tags.push(v.clone()),
+            _ => {}
+        }
+    }
+
+    let too_long = |v: &Option<String>, max| matches!(v, Some(v) if v.chars().count() > max);
+    let message = if too_long(&filter.search, MAX_SEARCH_LENGTH) {
+        "search is too long"
+    } else if too_long(&filter.category, MAX_SLUG_LENGTH) {
+        "category is too long"
+    } else if filter.tags.len() > MAX_TAGS {
+        "too many tags"
+    } else if filter
+        .tags
+        .iter()
+        .any(|t| t.chars().count() > MAX_TAG_LENGTH)
+    {
+        "tag is too long"
+    } else {
+        return Ok(filter);
+    };
+
+    Err((StatusCode::BAD_REQUEST, Some(json!({ "message": message }))))
+}
+
+#[derive(Deserialize)]
+struct PostVariantRequest {
+    sku: String,
+    price: i32,
+    quantity: i32,
+    size: Option<String>,
+    #[serde(rename(deserialize = "weightGrams"))]
+    weight_grams: Option<i32>,
+}
+
+impl PostVariantRequest {
+    fn new_variant(&self) -> NewVariant<'_> {
+        NewVariant {
+            sku: &self.sku,
+            price: self.price,
+            quantity: self.quantity,
+            size: self.size.as_deref(),
+            weight_grams: self.weight_grams,
+        }
+    }
+}
+
+impl Validate for PostVariantRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("sku", &self.sku)
+            .max_length("sku", &self.sku, MAX_SKU_LENGTH);
+        v.range("price", self.price.into(), 0, i32::MAX.into());
+        v.range("quantity", self.quantity.into(), 0, i32::MAX.into());
+        if let Some(size) = &self.size {
+            v.max_length("size", size, MAX_SIZE_LENGTH);
+        }
+        if let Some(weight_grams) = self.weight_grams {
+            v.range("weightGrams", weight_grams.into(), 0, i32::MAX.into());
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PostInventoryRequest {
+    name: String,
+    #[serde(default, rename(deserialize = "imageUrl"))]
+    image_url: String,
+    #[serde(default)]
+    description: String,
+    #[serde(rename(deserialize = "categoryId"))]
+    category_id: Option<i64>,
+    #[serde(default)]
+    tags: Vec<String>,
+    variants: Vec<PostVariantRequest>,
+}
+
+fn validate_tags(v: &mut Validator, tags: &[String]) {
+    if tags.len() > MAX_TAGS {
+        v.error(
+            "tags",
+            "too_long",
+            format!("tags must have at most {} items", MAX_TAGS),
+        );
+    }
+    for (i, tag) in tags.iter().enumerate() {
+        let field = format!("tags[{}]", i);
+        v.required(&field, tag)
+            .max_length(&field, tag, MAX_TAG_LENGTH);
+    }
+}
+
+impl Validate for PostInventoryRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name)
+            .max_length("name", &self.name, MAX_NAME_LENGTH);
+        v.max_length("imageUrl", &self.image_url, MAX_IMAGE_URL_LENGTH);
+        v.max_length("description", &self.description, MAX_DESCRIPTION_LENGTH);
+        if let Some(category_id) = self.category_id {
+            v.positive("categoryId", category_id);
+        }
+        validate_tags(v, &self.tags);
+        if self.variants.is_empty() {
+            v.error("variants", "required", "variants is required".into());
+        } else if self.variants.len() > MAX_VARIANTS {
+            v.error(
+                "variants",
+                "too_long",
+                format!("variants must have at most {} items", MAX_VARIANTS),
+            );
+        }
+        for (i, variant) in self.variants.iter().enumerate() {
+            v.nested(&format!("variants[{}]", i), variant);
+        }
+    }
+}
+
+/// Tells a field sent as null, `Some(None)`, apart from one left out, `None`.
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+struct PatchInventoryRequest {
+    name: Option<String>,
+    #[serde(rename(deserialize = "imageUrl"))]
+    image_url: Option<String>,
+    description: Option<String>,
+    /// null takes the product out of its category.
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        rename(deserialize = "categoryId")
+    )]
+    category_id: Option<Option<i64>>,
+    tags: Option<Vec<String>>,
+}
+
+impl Validate for PatchInventoryRequest {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(name) = &self.name {
+            v.required("name", name)
+                .max_length("name", name, MAX_NAME_LENGTH);
+        }
+        if let Some(image_url) = &self.image_url {
+            v.max_length("imageUrl", image_url, MAX_IMAGE_URL_LENGTH);
+        }
+        if let Some(description) = &self.description {
+            v.max_length("description", description, MAX_DESCRIPTION_LENGTH);
+        }
+        if let Some(Some(category_id)) = self.category_id {
+            v.positive("categoryId", category_id);
+        }
+        if let Some(tags) = &self.tags {
+            validate_tags(v, tags);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchVariantRequest {
+    sku: Option<String>,
+    price: Option<i32>,
+    quantity: Option<i32>,
+    size: Option<String>,
+    #[serde(rename(deserialize = "weightGrams"))]
+    weight_grams: Option<i32>,
+}
+
+impl Validate for PatchVariantRequest {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(sku) = &self.sku {
+            v.required("sku", sku)
+                .max_length("sku", sku, MAX_SKU_LENGTH);
+        }
+        if let Some(price) = self.price {
+            v.range("price", price.into(), 0, i32::MAX.into());
+        }
+        if let Some(quantity) = self.quantity {
+            v.range("quantity", quantity.into(), 0, i32::MAX.into());
+        }
+        if let Some(size) = &self.size {
+            v.max_length("size", size, MAX_SIZE_LENGTH);
+        }
+        if let Some(weight_grams) = self.weight_grams {
+            v.range("weightGrams", weight_grams.into(), 0, i32::MAX.into());
+        }
+    }
+}
+
+fn product_not_found() -> (StatusCode, Option<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Some(json!({ "message": "product not found" })),
+    )
+}
+
+fn variant_not_found() -> (StatusCode, Option<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Some(json!({ "message": "variant not found" })),
+    )
+}
+
+/// SKUs are unique across all products, and a product's category has to
+/// exist.
+fn map_inventory_err(e: sqlx::Error) -> (StatusCode, Option<serde_json::Value>) {
+    match e {
+        sqlx::Error::Database(e) if e.code().as_deref() == Some("23505") => (
+            StatusCode::CONFLICT,
+            Some(json!({ "message": "sku is already in use" })),
+        ),
+        sqlx::Error::Database(e) if e.code().as_deref() == Some("23503") => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Some(json!({ "message": "category not found" })),
+        ),
+        e => {
+            log::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        }
+    }
+}
+
 pub async fn get_inventory(
     pool: &PgPool,
     query: Option<&str>,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let query = query.unwrap_or("");
-    let parsed = UrlQuery::new(query, ["quantity", "id", "price", "createdAt"]).map_err(|e| {
+    let (params, query) = split_params(
+        query.unwrap_or(""),
+        &["search", "category", "tag", "facets"],
+    );
+    let filter = get_filter(&params)?;
+    let facets = params.iter().any(|(k, v)| k == "facets" && v == "true");
+
+    let parsed = match filter.search {
+        Some(_) => UrlQuery::new(
+            &sort_by_rank(query),
+            ["quantity", "id", "price", "createdAt", "rank"],
+        ),
+        None => UrlQuery::new(&query, ["quantity", "id", "price", "createdAt"]),
+    }
+    .map_err(|e| {
         log::debug!("{:?}", e);
         (
             StatusCode::BAD_REQUEST,
@@ -22,29 +333,827 @@ pub async fn get_inventory(
         Err((StatusCode::BAD_REQUEST, Some(json!({ "message": e }))))?
     }
 
-    let inventory = Inventory::get(pool, parsed).await.map_err(|e| {
+    let map_err = |e: Either<sqlx::Error, ParseError>| {
         log::debug!("{}", e);
         match e {
             Either::Right(_) => (StatusCode::BAD_REQUEST, None),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, None),
         }
+    };
+
+    let items = match filter.search {
+        Some(_) => {
+            let results = Inventory::search(pool, &filter, parsed)
+                .await
+                .map_err(map_err)?;
+            serde_json::to_value(&results)
+        }
+        None => {
+            let inventory = Inventory::get(pool, &filter, parsed)
+                .await
+                .map_err(map_err)?;
+            serde_json::to_value(&inventory)
+        }
+    }
+    .map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    // Listings stay an array unless the facets are asked for:
+    let res = if facets {
+        let facets = Facets::get(pool, &filter).await.map_err(|e| {
+            log::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        })?;
+        json!({ "items": items, "facets": facets })
+    } else {
+        items
+    };
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res.to_string());
+
+    Ok(response)
+}
+
+pub async fn get_inventory_by_id(
+    pool: &PgPool,
+    id: &str,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| product_not_found())?;
+
+    let product = Product::from_id(pool, id).await.map_err(|e| match e {
+        sqlx::Error::RowNotFound => product_not_found(),
+        e => {
+            log::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        }
+    })?;
+
+    let res = serde_json::to_string(&product).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
-    let res = serde_json::to_string(&inventory).map_err(|e| {
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+pub async fn post_inventory(
+    pool: &PgPool,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    let r: PostInventoryRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    validate(&r)?;
+
+    let variants: Vec<NewVariant> = r.variants.iter().map(|v| v.new_variant()).collect();
+
+    let product = Inventory::new(
+        pool,
+        &r.name,
+        &r.image_url,
+        &r.description,
+        r.category_id,
+        &r.tags,
+        &variants,
+    )
+    .await
+    .map_err(map_inventory_err)?;
+
+    let res = serde_json::to_string(&product).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    *response.status_mut() = StatusCode::CREATED;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+pub async fn patch_inventory(
+    pool: &PgPool,
+    id: &str,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| product_not_found())?;
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::debug!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
     })?;
 
+    let r: PatchInventoryRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    validate(&r)?;
+
+    let product = Inventory::update(
+        pool,
+        id,
+        r.name.as_deref(),
+        r.image_url.as_deref(),
+        r.description.as_deref(),
+        r.category_id,
+        r.tags.as_deref(),
+    )
+    .await
+    .map_err(map_inventory_err)?
+    .ok_or_else(product_not_found)?;
+
+    let res = serde_json::to_string(&product).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+pub async fn delete_inventory(
+    pool: &PgPool,
+    id: &str,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| product_not_found())?;
+
+    let deleted = Inventory::delete(pool, id).await.map_err(|e| {
+        log::error!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    if !deleted {
+        Err(product_not_found())?
+    }
+
     *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from("{\"message\": \"success\"}");
+
+    Ok(response)
+}
+
+pub async fn post_variant(
+    pool: &PgPool,
+    id: &str,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| product_not_found())?;
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    let r: PostVariantRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    validate(&r)?;
+
+    let variant = Variant::new(pool, id, &r.new_variant())
+        .await
+        .map_err(map_inventory_err)?
+        .ok_or_else(product_not_found)?;
+
+    let res = serde_json::to_string(&variant).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    *response.status_mut() = StatusCode::CREATED;
     *response.body_mut() = Body::from(res);
 
     Ok(response)
 }
 
+pub async fn patch_variant(
+    pool: &PgPool,
+    id: &str,
+    variant_id: &str,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| variant_not_found())?;
+    let variant_id: i64 = variant_id.parse().map_err(|_| variant_not_found())?;
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    let r: PatchVariantRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    validate(&r)?;
+
+    let changes = VariantChanges {
+        sku: r.sku.as_deref(),
+        price: r.price,
+        quantity: r.quantity,
+        size: r.size.as_deref(),
+        weight_grams: r.weight_grams,
+    };
+
+    let variant = Variant::update(pool, id, variant_id, &changes)
+        .await
+        .map_err(map_inventory_err)?
+        .ok_or_else(variant_not_found)?;
+
+    let res = serde_json::to_string(&variant).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+pub async fn delete_variant(
+    pool: &PgPool,
+    id: &str,
+    variant_id: &str,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let id: i64 = id.parse().map_err(|_| variant_not_found())?;
+    let variant_id: i64 = variant_id.parse().map_err(|_| variant_not_found())?;
+
+    let deleted = Variant::delete(pool, id, variant_id).await.map_err(|e| {
+        log::error!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    if !deleted {
+        Err(variant_not_found())?
+    }
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from("{\"message\": \"success\"}");
+
+    Ok(response)
+}
+
 #[cfg(test)]
 mod test {
+    use dblib::shop::inventory::{Facets, Filter};
+    use hyper::{Body, Response, StatusCode};
+    use serde_json::json;
+
+    use super::{
+        delete_inventory, delete_variant, get_inventory, get_inventory_by_id, patch_inventory,
+        patch_variant, post_inventory, post_variant, sort_by_rank, split_params,
+    };
+
+    async fn body_json(res: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
     #[sqlx::test(fixtures("shop"))]
     async fn test_get_inventory(_pool: sqlx::PgPool) -> sqlx::Result<()> {
         Ok(())
     }
+
+    #[test]
+    fn test_split_params() {
+        let keys = ["search", "tag"];
+        let param = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+
+        assert_eq!(
+            split_params("search=earl+grey&limit=10&offset=0", &keys),
+            (
+                vec![param("search", "earl grey")],
+                "limit=10&offset=0".into()
+            )
+        );
+        assert_eq!(
+            split_params("price=lt-500&search=%22english%20breakfast%22", &keys),
+            (
+                vec![param("search", "\"english breakfast\"")],
+                "price=lt-500".into()
+            )
+        );
+        assert_eq!(
+            split_params("tag=organic&tag=loose+leaf", &keys),
+            (
+                vec![param("tag", "organic"), param("tag", "loose leaf")],
+                "".into()
+            )
+        );
+        assert_eq!(
+            split_params("search=&id=eq-1", &keys),
+            (vec![], "id=eq-1".into())
+        );
+        assert_eq!(split_params("", &keys), (vec![], "".into()));
+    }
+
+    #[test]
+    fn test_sort_by_rank() {
+        assert_eq!(sort_by_rank("".into()), "sort=rank-desc");
+        assert_eq!(
+            sort_by_rank("limit=10&offset=0".into()),
+            "limit=10&offset=0&sort=rank-desc"
+        );
+        assert_eq!(sort_by_rank("sort=id-asc".into()), "sort=id-asc");
+    }
+
+    fn count(facets: &Facets, kind: &str, key: &str, value: &str) -> Option<i64> {
+        serde_json::to_value(facets).unwrap()[kind]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f[key] == value)
+            .map(|f| f["count"].as_i64().unwrap())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_facets(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let facets = Facets::get(&pool, &Filter::default()).await?;
+        // Categories count their subcategories' products:
+        assert_eq!(count(&facets, "categories", "slug", "tea"), Some(2));
+        assert_eq!(count(&facets, "categories", "slug", "earl-grey"), Some(1));
+        assert_eq!(count(&facets, "tags", "name", "organic"), Some(1));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_facets_category(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let filter = Filter {
+            category: Some("english-breakfast".into()),
+            ..Default::default()
+        };
+        let facets = Facets::get(&pool, &filter).await?;
+        assert_eq!(count(&facets, "categories", "slug", "black-tea"), Some(1));
+        assert_eq!(count(&facets, "categories", "slug", "earl-grey"), None);
+        assert_eq!(count(&facets, "tags", "name", "organic"), None);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_facets_search_and_tags(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let filter = Filter {
+            search: Some("earl grey".into()),
+            tags: vec!["organic".into(), "plastic-free".into()],
+            ..Default::default()
+        };
+        let facets = Facets::get(&pool, &filter).await?;
+        assert_eq!(count(&facets, "categories", "slug", "tea"), Some(1));
+        assert_eq!(count(&facets, "tags", "name", "plastic-free"), Some(1));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_facets_category_cycle(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // A cycle in the categories still ends:
+        sqlx::query("UPDATE categories SET parent_id = 3 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let filter = Filter {
+            category: Some("tea".into()),
+            ..Default::default()
+        };
+        let facets = Facets::get(&pool, &filter).await?;
+        assert_eq!(count(&facets, "categories", "slug", "earl-grey"), Some(2));
+
+        Ok(())
+    }
+
+    /// Adds Assam, in black tea with one variant, returns it as the response
+    /// has it.
+    async fn post_assam(pool: &sqlx::PgPool) -> serde_json::Value {
+        let mut body = Body::from(
+            r#"{"name": "Assam", "categoryId": 2, "tags": ["organic", "loose leaf"], "variants": [{"sku": "ASSAM-40", "price": 250, "quantity": 10, "size": "40 bags"}]}"#,
+        );
+        let res = post_inventory(pool, &mut body, Response::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        body_json(res).await
+    }
+
+    fn id(value: &serde_json::Value) -> String {
+        value["id"].as_i64().unwrap().to_string()
+    }
+
+    async fn patch(
+        pool: &sqlx::PgPool,
+        id: &str,
+        body: &'static str,
+    ) -> Result<serde_json::Value, (StatusCode, Option<serde_json::Value>)> {
+        let res = patch_inventory(pool, id, &mut Body::from(body), Response::default()).await?;
+        Ok(body_json(res).await)
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_inventory(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let product = post_assam(&pool).await;
+        assert_eq!(product["name"], "Assam");
+        assert_eq!(product["categoryId"], 2);
+        assert_eq!(product["tags"], json!(["loose leaf", "organic"]));
+        assert_eq!(product["variants"][0]["size"], "40 bags");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_inventory_no_variants(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(r#"{"name": "Assam", "variants": []}"#);
+        let (code, body) = post_inventory(&pool, &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["errors"][0]["field"], "variants");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_inventory_invalid_variant(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(
+            r#"{"name": "Assam", "variants": [{"sku": "ASSAM-40", "price": -1, "quantity": 10}]}"#,
+        );
+        let (code, body) = post_inventory(&pool, &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["errors"][0]["field"], "variants[0].price");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_inventory_sku_taken(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // SKUs are unique across products:
+        let mut body = Body::from(
+            r#"{"name": "Assam", "variants": [{"sku": "CLIPPER-EG-80", "price": 250, "quantity": 10}]}"#,
+        );
+        let (code, _) = post_inventory(&pool, &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_get_inventory_tag(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        post_assam(&pool).await;
+
+        let res = get_inventory(&pool, Some("tag=loose+leaf"), Response::default())
+            .await
+            .unwrap();
+        let items = body_json(res).await;
+        assert_eq!(items.as_array().unwrap().len(), 1);
+        assert_eq!(items[0]["name"], "Assam");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_get_inventory_by_id(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // The product comes with all its variants, its price is the lowest:
+        let res = get_inventory_by_id(&pool, "1", Response::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let product = body_json(res).await;
+        assert_eq!(product["name"], "Clipper Earl Grey");
+        assert_eq!(product["price"], 175);
+        assert_eq!(product["quantity"], 200);
+        assert_eq!(product["variants"].as_array().unwrap().len(), 2);
+        assert_eq!(product["variants"][0]["weightGrams"], 200);
+        assert_eq!(product["tags"], json!(["organic", "plastic-free"]));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_get_inventory_by_id_not_found(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let (code, body) = get_inventory_by_id(&pool, "999", Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["message"], "product not found");
+
+        let (code, _) = get_inventory_by_id(&pool, "tea", Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let product = patch(&pool, "1", r#"{"name": "Clipper Earl Grey Tea"}"#)
+            .await
+            .unwrap();
+        assert_eq!(product["name"], "Clipper Earl Grey Tea");
+        assert_eq!(product["categoryId"], 3);
+        assert_eq!(product["variants"].as_array().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory_tags(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let id = id(&post_assam(&pool).await);
+
+        // Tags are replaced, the category is kept if it isn't given:
+        let product = patch(&pool, &id, r#"{"tags": ["decaf"]}"#).await.unwrap();
+        assert_eq!(product["categoryId"], 2);
+        assert_eq!(product["tags"], json!(["decaf"]));
+
+        let product = patch(&pool, &id, r#"{"tags": []}"#).await.unwrap();
+        assert_eq!(product["tags"], json!([]));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory_invalid_tag(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let (code, body) = patch(&pool, "1", r#"{"tags": [""]}"#).await.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["errors"][0]["field"], "tags[0]");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory_category(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let product = patch(&pool, "1", r#"{"categoryId": 4}"#).await.unwrap();
+        assert_eq!(product["categoryId"], 4);
+        // The tags are kept if they aren't given:
+        assert_eq!(product["tags"], json!(["organic", "plastic-free"]));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory_no_category(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let product = patch(&pool, "1", r#"{"categoryId": null}"#).await.unwrap();
+        assert_eq!(product["categoryId"], serde_json::Value::Null);
+        assert_eq!(product["name"], "Clipper Earl Grey");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_inventory_category_not_found(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let (code, body) = patch(&pool, "1", r#"{"categoryId": 999}"#)
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["message"], "category not found");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_delete_inventory(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let res = delete_inventory(&pool, "1", Response::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let (code, _) = get_inventory_by_id(&pool, "1", Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        // Deleted products aren't listed, but stay for past orders:
+        let res = get_inventory(&pool, None, Response::default())
+            .await
+            .unwrap();
+        let listed = body_json(res).await;
+        assert!(listed.as_array().unwrap().iter().all(|p| p["id"] != 1));
+
+        let deleted: bool =
+            sqlx::query_scalar("SELECT deleted_at IS NOT NULL FROM inventory WHERE id = 1")
+                .fetch_one(&pool)
+                .await?;
+        assert!(deleted);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_delete_inventory_deleted(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        delete_inventory(&pool, "1", Response::default())
+            .await
+            .unwrap();
+
+        let (code, _) = delete_inventory(&pool, "1", Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        let (code, _) = patch(&pool, "1", r#"{"name": "Assam"}"#).await.unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        let mut body = Body::from(r#"{"sku": "ASSAM-160", "price": 800, "quantity": 5}"#);
+        let (code, _) = post_variant(&pool, "1", &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_variant(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(
+            r#"{"sku": "CLIPPER-EG-160", "price": 550, "quantity": 5, "size": "160 bags", "weightGrams": 400}"#,
+        );
+        let res = post_variant(&pool, "1", &mut body, Response::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let variant = body_json(res).await;
+        assert_eq!(variant["sku"], "CLIPPER-EG-160");
+        assert_eq!(variant["weightGrams"], 400);
+
+        let res = get_inventory_by_id(&pool, "1", Response::default())
+            .await
+            .unwrap();
+        let product = body_json(res).await;
+        assert_eq!(product["quantity"], 205);
+        assert_eq!(product["variants"].as_array().unwrap().len(), 3);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_variant(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(r#"{"price": 275}"#);
+        let res = patch_variant(&pool, "1", "1", &mut body, Response::default())
+            .await
+            .unwrap();
+        let variant = body_json(res).await;
+        assert_eq!(variant["price"], 275);
+        assert_eq!(variant["quantity"], 100);
+        assert_eq!(variant["size"], "80 bags");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_variant_sku_taken(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(r#"{"sku": "CLIPPER-EG-40"}"#);
+        let (code, _) = patch_variant(&pool, "1", "1", &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_patch_variant_other_product(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // Variants are only found under their own product, variant 2 is
+        // product 2's:
+        let mut body = Body::from(r#"{"price": 300}"#);
+        let (code, body) = patch_variant(&pool, "1", "2", &mut body, Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["message"], "variant not found");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_delete_variant(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let res = delete_variant(&pool, "1", "3", Response::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = get_inventory_by_id(&pool, "1", Response::default())
+            .await
+            .unwrap();
+        let product = body_json(res).await;
+        assert_eq!(product["price"], 299);
+        assert_eq!(product["quantity"], 100);
+        assert_eq!(product["variants"].as_array().unwrap().len(), 1);
+
+        let (code, _) = delete_variant(&pool, "1", "3", Response::default())
+            .await
+            .unwrap_err();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    /// The IDs of the products listed for the query, in order.
+    async fn search(pool: &sqlx::PgPool, query: &str) -> Vec<i64> {
+        let res = get_inventory(pool, Some(query), Response::default())
+            .await
+            .unwrap();
+        body_json(res)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    /// Both products match "breakfast", product 2 in its name and product 1
+    /// in its description.
+    async fn describe_breakfast(pool: &sqlx::PgPool) -> sqlx::Result<()> {
+        sqlx::query("UPDATE inventory SET description = 'Good with breakfast' WHERE id = 1")
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_search(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        describe_breakfast(&pool).await?;
+
+        // A match in the name ranks above one in the description:
+        let res = get_inventory(&pool, Some("search=breakfast"), Response::default())
+            .await
+            .unwrap();
+        let items = body_json(res).await;
+        assert_eq!(items[0]["id"], 2);
+        assert_eq!(items[1]["id"], 1);
+        assert_eq!(
+            items[0]["nameHighlight"],
+            "Twinings English <b>Breakfast</b>"
+        );
+        assert_eq!(
+            items[1]["descriptionHighlight"],
+            "Good with <b>breakfast</b>"
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_search_no_match(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        assert_eq!(search(&pool, "search=bergamot").await, Vec::<i64>::new());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_search_filter(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        describe_breakfast(&pool).await?;
+
+        assert_eq!(search(&pool, "search=breakfast&price=lt-500").await, [1]);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_search_paging(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        describe_breakfast(&pool).await?;
+
+        // Paging keeps the best matches first:
+        assert_eq!(search(&pool, "search=breakfast&limit=1").await, [2]);
+        assert_eq!(
+            search(&pool, "search=breakfast&limit=1&offset=1").await,
+            [1]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_search_sort(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        describe_breakfast(&pool).await?;
+
+        assert_eq!(search(&pool, "search=breakfast&sort=id-asc").await, [1, 2]);
+
+        Ok(())
+    }
 }
@@ -1,15 +1,63 @@
 pub mod address;
 pub mod cart;
+pub mod categories;
 pub mod inventory;
 pub mod orders;
 
 use address::{get_address, post_address};
-use apilib::{set_response_v2, App};
+use apilib::{path_segments, set_response_v2, App};
 use cart::{delete_cart, get_cart, patch_cart, post_cart};
+use categories::get_categories;
 use hyper::{http::HeaderValue, Body, Method, Request, Response, StatusCode};
-use inventory::get_inventory;
-use orders::{get_orders, post_orders};
+use inventory::{
+    delete_inventory, delete_variant, get_inventory, get_inventory_by_id, patch_inventory,
+    patch_variant, post_inventory, post_variant,
+};
+use orders::{get_orders, patch_orders, post_orders};
 use std::{convert::Infallible, sync::Arc};
+use towerlib::auth::{check_access, Permission};
+
+/// Who can call each route, checked before the request is handled.
+fn permission(method: &Method, path: &str) -> Permission {
+    match (method, path_segments(path).as_slice()) {
+        (_, ["address"]) => Permission::User,
+        (&Method::GET, ["orders"]) => Permission::User,
+        (&Method::POST, ["orders"]) => Permission::Verified,
+        (&Method::PATCH, ["orders"]) => Permission::Admin,
+        (&Method::POST, ["inventory"]) => Permission::Admin,
+        (&Method::PATCH, ["inventory", _]) | (&Method::DELETE, ["inventory", _]) => {
+            Permission::Admin
+        }
+        (&Method::POST, ["inventory", _, "variants"]) => Permission::Admin,
+        (&Method::PATCH, ["inventory", _, "variants", _])
+        | (&Method::DELETE, ["inventory", _, "variants", _]) => Permission::Admin,
+        _ => Permission::Public,
+    }
+}
+
+/// Which API key scope can call each route, keys can't call routes without
+/// one.
+fn scope(method: &Method, path: &str) -> Option<&'static str> {
+    match (method, path_segments(path).as_slice()) {
+        (&Method::GET, ["orders"]) => Some("orders:read"),
+        (&Method::PATCH, ["orders"]) => Some("orders:write"),
+        (&Method::POST, ["inventory"]) => Some("inventory:write"),
+        (&Method::PATCH, ["inventory", _]) | (&Method::DELETE, ["inventory", _]) => {
+            Some("inventory:write")
+        }
+        (&Method::POST, ["inventory", _, "variants"]) => Some("inventory:write"),
+        (&Method::PATCH, ["inventory", _, "variants", _])
+        | (&Method::DELETE, ["inventory", _, "variants", _]) => Some("inventory:write"),
+        _ => None,
+    }
+}
+
+/// A user ID for tests that won't clash with other tests' users, which share
+/// Redis.
+#[cfg(test)]
+pub(crate) fn rand_user_id() -> i64 {
+    (uuid::Uuid::new_v4().as_u128() >> 65) as i64
+}
 
 pub async fn handle(app: Arc<App>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
     let mut response = Response::new(Body::empty());
@@ -19,51 +67,90 @@ pub async fn handle(app: Arc<App>, req: Request<Body>) -> Result<Response<Body>,
 
     let (parts, mut body) = req.into_parts();
 
-    let result = match (parts.method, parts.uri.path()) {
-        (Method::GET, "/inventory") => get_inventory(&app.pool, parts.uri.query(), response).await,
-        (Method::POST, "/address") => post_address(&app.pool, &mut body, response).await,
-        (Method::GET, "/address") => get_address(&app.pool, parts.uri.query(), response).await,
-        (Method::GET, "/cart") => {
-            get_cart(app.redis.as_ref().unwrap(), &parts.headers, response).await
+    let permission = permission(&parts.method, parts.uri.path());
+    let scope = scope(&parts.method, parts.uri.path());
+    if let Err(e) = check_access(permission, scope, &parts.extensions) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let result = match (parts.method, path_segments(parts.uri.path()).as_slice()) {
+        (Method::GET, ["inventory"]) => get_inventory(&app.pool, parts.uri.query(), response).await,
+        (Method::GET, ["categories"]) => get_categories(&app.pool, response).await,
+        (Method::GET, ["inventory", id]) => get_inventory_by_id(&app.pool, id, response).await,
+        (Method::POST, ["inventory"]) => post_inventory(&app.pool, &mut body, response).await,
+        (Method::PATCH, ["inventory", id]) => {
+            patch_inventory(&app.pool, id, &mut body, response).await
+        }
+        (Method::DELETE, ["inventory", id]) => delete_inventory(&app.pool, id, response).await,
+        (Method::POST, ["inventory", id, "variants"]) => {
+            post_variant(&app.pool, id, &mut body, response).await
+        }
+        (Method::PATCH, ["inventory", id, "variants", variant_id]) => {
+            patch_variant(&app.pool, id, variant_id, &mut body, response).await
+        }
+        (Method::DELETE, ["inventory", id, "variants", variant_id]) => {
+            delete_variant(&app.pool, id, variant_id, response).await
+        }
+        (Method::POST, ["address"]) => {
+            post_address(&app.pool, &parts.extensions, &mut body, response).await
         }
-        (Method::POST, "/cart") => {
+        (Method::GET, ["address"]) => {
+            get_address(&app.pool, &parts.extensions, parts.uri.query(), response).await
+        }
+        (Method::GET, ["cart"]) => {
+            get_cart(
+                app.redis.as_ref().unwrap(),
+                &parts.headers,
+                &parts.extensions,
+                response,
+            )
+            .await
+        }
+        (Method::POST, ["cart"]) => {
             post_cart(
                 app.redis.as_ref().unwrap(),
                 &parts.headers,
+                &parts.extensions,
                 &mut body,
                 response,
             )
             .await
         }
-        (Method::PATCH, "/cart") => {
+        (Method::PATCH, ["cart"]) => {
             patch_cart(
                 app.redis.as_ref().unwrap(),
                 &parts.headers,
+                &parts.extensions,
                 &mut body,
                 response,
             )
             .await
         }
-        (Method::DELETE, "/cart") => {
+        (Method::DELETE, ["cart"]) => {
             delete_cart(
                 app.redis.as_ref().unwrap(),
                 &parts.headers,
+                &parts.extensions,
                 &mut body,
                 response,
             )
             .await
         }
-        (Method::POST, "/orders") => {
+        (Method::POST, ["orders"]) => {
             post_orders(
                 &app.pool,
                 app.redis.as_ref().unwrap(),
+                &parts.extensions,
                 &mut body,
                 &parts.headers,
                 response,
             )
             .await
         }
-        (Method::GET, "/orders") => get_orders(&app.pool, parts.uri.query(), response).await,
+        (Method::GET, ["orders"]) => {
+            get_orders(&app.pool, &parts.extensions, parts.uri.query(), response).await
+        }
+        (Method::PATCH, ["orders"]) => patch_orders(&app.pool, &mut body, response).await,
         _ => {
             *response.status_mut() = StatusCode::NOT_FOUND;
             Ok(response)
@@ -77,3 +164,65 @@ pub async fn handle(app: Arc<App>, req: Request<Body>) -> Result<Response<Body>,
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::Method;
+    use towerlib::auth::Permission;
+
+    use super::{permission, scope};
+
+    #[test]
+    fn test_permission() {
+        assert_eq!(permission(&Method::GET, "/inventory"), Permission::Public);
+        assert_eq!(permission(&Method::POST, "/cart"), Permission::Public);
+        assert_eq!(permission(&Method::POST, "/address"), Permission::User);
+        assert_eq!(permission(&Method::GET, "/orders"), Permission::User);
+        assert_eq!(permission(&Method::POST, "/orders"), Permission::Verified);
+        assert_eq!(permission(&Method::PATCH, "/orders"), Permission::Admin);
+        assert_eq!(permission(&Method::GET, "/inventory/1"), Permission::Public);
+        assert_eq!(permission(&Method::GET, "/categories"), Permission::Public);
+        assert_eq!(permission(&Method::POST, "/inventory"), Permission::Admin);
+        assert_eq!(
+            permission(&Method::PATCH, "/inventory/1"),
+            Permission::Admin
+        );
+        assert_eq!(
+            permission(&Method::DELETE, "/inventory/1"),
+            Permission::Admin
+        );
+        assert_eq!(
+            permission(&Method::POST, "/inventory/1/variants"),
+            Permission::Admin
+        );
+        assert_eq!(
+            permission(&Method::PATCH, "/inventory/1/variants/2"),
+            Permission::Admin
+        );
+        assert_eq!(
+            permission(&Method::DELETE, "/inventory/1/variants/2"),
+            Permission::Admin
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(scope(&Method::GET, "/orders"), Some("orders:read"));
+        assert_eq!(scope(&Method::PATCH, "/orders"), Some("orders:write"));
+        assert_eq!(scope(&Method::POST, "/orders"), None);
+        assert_eq!(scope(&Method::POST, "/address"), None);
+        assert_eq!(
+            scope(&Method::PATCH, "/inventory/1"),
+            Some("inventory:write")
+        );
+        assert_eq!(
+            scope(&Method::POST, "/inventory/1/variants"),
+            Some("inventory:write")
+        );
+        assert_eq!(
+            scope(&Method::DELETE, "/inventory/1/variants/2"),
+            Some("inventory:write")
+        );
+        assert_eq!(scope(&Method::GET, "/inventory"), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{convert::Infallible, net::SocketAddr};
+use std::{convert::Infallible, env, net::SocketAddr};
 
 use apilib::App;
 use hyper::{
@@ -8,23 +8,38 @@ use hyper::{
 };
 use redis::Client as RedisClient;
 use tower_http::cors::{Any, Cors};
-use towerlib::{logging::Logging, session::Session};
+use towerlib::{
+    auth::Auth,
+    keys::Verifier,
+    logging::Logging,
+    session::{Session, SessionCookie},
+};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
     let pool = dblib::connect("shop").await.unwrap();
+    // API keys are kept with the users:
+    let users = dblib::connect("users").await.unwrap();
     let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
 
-    let app = App::new(pool, Some(redis));
+    let app = App::new(pool, Some(redis.clone()));
+
+    // Tokens are signed by the users service, which publishes its keys:
+    let jwks_url = env::var("JWKS_URL").expect("JWKS_URL must be set");
+    let verifier = Verifier::from_url(jwks_url.parse().expect("JWKS_URL must be a valid URL"));
 
     let make_service = make_service_fn(move |_: &AddrStream| {
         // Clone for each invocation of make_service
         let app = app.clone();
+        let users = users.clone();
+        let redis = redis.clone();
+        let verifier = verifier.clone();
 
         let svc = service_fn(move |req| shop::handle(app.clone(), req));
-        let svc = Session::new(svc);
+        let svc = Session::new(svc, redis.clone()).with_cookie(SessionCookie::from_env());
+        let svc = Auth::new(svc, redis, verifier).with_api_keys(users);
         let svc = Logging::new(svc);
         let svc = Cors::new(svc)
             .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
@@ -33,7 +48,8 @@ async fn main() {
         async move { Ok::<_, Infallible>(svc) }
     });
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
+    // The users service is on 8080:
+    let addr = SocketAddr::from(([0, 0, 0, 0], 8081));
     let server = Server::bind(&addr).serve(make_service);
 
     if let Err(e) = server.await {
@@ -1,28 +1,44 @@
-use dblib::shop::orders::{Order, OrderDetail, OrderRequest};
-use hyper::{Body, HeaderMap, Response, StatusCode};
+use apilib::{
+    scope_query,
+    validate::{validate, Validate, Validator},
+};
+use dblib::{
+    shop::orders::{Order, OrderDetail, OrderRequest, Unavailable, ORDER_STATUSES},
+    users::api_keys::ApiKey,
+};
+use hyper::{http::Extensions, Body, HeaderMap, Response, StatusCode};
 use query::UrlQuery;
 use redis::Client as RedisClient;
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::{Either, PgPool};
+use towerlib::auth::get_claims;
+use uuid::Uuid;
 
 use crate::cart;
 
 #[derive(Deserialize)]
 struct PostOrdersRequestV2 {
-    #[serde(rename(deserialize = "userId"))]
-    user_id: i64,
     #[serde(rename(deserialize = "addressId"))]
     address_id: i64,
 }
 
+impl Validate for PostOrdersRequestV2 {
+    fn validate(&self, v: &mut Validator) {
+        v.positive("addressId", self.address_id);
+    }
+}
+
 pub async fn post_orders(
     pool: &PgPool,
     redis: &RedisClient,
+    extensions: &Extensions,
     body: &mut Body,
     headers: &HeaderMap,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let claims = get_claims(extensions)?;
+
     let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
         log::debug!("{}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, None)
@@ -33,7 +49,9 @@ pub async fn post_orders(
         (StatusCode::UNPROCESSABLE_ENTITY, None)
     })?;
 
-    let cart = cart::get_cart_hashset(redis, headers).await?;
+    validate(&r)?;
+
+    let cart = cart::get_cart_hashset(redis, headers, extensions).await?;
 
     let mut request = Vec::new();
     for item in &cart {
@@ -45,11 +63,26 @@ pub async fn post_orders(
         request.push(i);
     }
 
-    let order_id = Order::new(pool, r.user_id, r.address_id, request)
+    let order_id = Order::new(pool, claims.id, r.address_id, request)
         .await
-        .map_err(|e| {
-            log::error!("{}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        .map_err(|e| match e {
+            Either::Right(Unavailable::Address) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(json!({ "message": "address not found" })),
+            ),
+            Either::Right(e @ Unavailable::Variant(variant_id)) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(json!({ "message": e.to_string(), "variantId": variant_id })),
+            ),
+            // The variants_quantity check:
+            Either::Left(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23514") => (
+                StatusCode::CONFLICT,
+                Some(json!({ "message": "Not enough in stock" })),
+            ),
+            Either::Left(e) => {
+                log::error!("{}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, None)
+            }
         })?;
 
     let res = serde_json::json!({ "orderId": order_id.to_string() });
@@ -66,11 +99,20 @@ pub async fn post_orders(
 
 pub async fn get_orders(
     pool: &PgPool,
+    extensions: &Extensions,
     query: Option<&str>,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
-    let query = query.unwrap_or("");
-    let mut parsed = UrlQuery::new(query, ["userId", "id", "createdAt"]).map_err(|e| {
+    // Only the user's own orders, API keys with `orders:read` can see all of
+    // them:
+    let query = match extensions.get::<ApiKey>() {
+        Some(_) => query.unwrap_or("").to_owned(),
+        None => {
+            let claims = get_claims(extensions)?;
+            scope_query(query, "userId", &claims.id.to_string())
+        }
+    };
+    let mut parsed = UrlQuery::new(&query, ["userId", "id", "createdAt"]).map_err(|e| {
         log::debug!("{:?}", e);
         (
             StatusCode::BAD_REQUEST,
@@ -128,3 +170,241 @@ pub async fn get_orders(
 
     Ok(response)
 }
+
+#[derive(Deserialize)]
+struct PatchOrdersRequest {
+    id: String,
+    status: String,
+}
+
+impl Validate for PatchOrdersRequest {
+    fn validate(&self, v: &mut Validator) {
+        if Uuid::parse_str(&self.id).is_err() {
+            v.error("id", "invalid_id", "id is not a valid order ID".into());
+        }
+        v.one_of("status", &self.status, &ORDER_STATUSES);
+    }
+}
+
+pub async fn patch_orders(
+    pool: &PgPool,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    let r: PatchOrdersRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    validate(&r)?;
+
+    let id = Uuid::parse_str(&r.id).map_err(|e| {
+        log::debug!("{}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, None)
+    })?;
+
+    let updated = Order::set_status(pool, id, &r.status).await.map_err(|e| {
+        log::error!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, None)
+    })?;
+
+    if !updated {
+        Err((
+            StatusCode::NOT_FOUND,
+            Some(json!({ "message": "order not found" })),
+        ))?
+    }
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from("{\"message\": \"success\"}");
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use dblib::shop::orders::Order;
+    use hyper::{http::Extensions, Body, HeaderMap, Response, StatusCode};
+    use redis::{AsyncCommands, Client as RedisClient};
+    use serde_json::json;
+    use towerlib::{
+        auth::{Claims, Role},
+        cart::user_cart_key,
+        session::SESSION_ID,
+    };
+    use uuid::Uuid;
+
+    use super::post_orders;
+    use crate::rand_user_id;
+
+    /// Orders the variant from a new user's cart to one of their addresses,
+    /// returns the user's ID.
+    async fn order(
+        pool: &sqlx::PgPool,
+        variant_id: i64,
+        quantity: i32,
+    ) -> (
+        i64,
+        Result<Response<Body>, (StatusCode, Option<serde_json::Value>)>,
+    ) {
+        let user_id = rand_user_id();
+        let address_id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO address (user_id, first_name, last_name, address_1, address_2, postcode, city)
+            VALUES ($1, 'bob', 'smith', '1 bob st', '', 'm1abc', 'manchester')
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        (
+            user_id,
+            order_to(pool, user_id, address_id, variant_id, quantity).await,
+        )
+    }
+
+    async fn order_to(
+        pool: &sqlx::PgPool,
+        user_id: i64,
+        address_id: i64,
+        variant_id: i64,
+        quantity: i32,
+    ) -> Result<Response<Body>, (StatusCode, Option<serde_json::Value>)> {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let item = json!({
+            "variantId": variant_id,
+            "name": "Teapot",
+            "price": 1000,
+            "imageUrl": "",
+            "quantity": quantity,
+        });
+        let mut con = redis.get_async_connection().await.unwrap();
+        con.sadd::<_, _, ()>(user_cart_key(user_id), item.to_string())
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SESSION_ID,
+            Uuid::new_v4().simple().to_string().parse().unwrap(),
+        );
+        let mut extensions = Extensions::new();
+        extensions.insert(Claims::new(
+            user_id,
+            "bob@smith.com".into(),
+            Role::User,
+            true,
+        ));
+
+        let mut body = Body::from(json!({ "addressId": address_id }).to_string());
+        let res = post_orders(
+            pool,
+            &redis,
+            &extensions,
+            &mut body,
+            &headers,
+            Response::default(),
+        )
+        .await;
+
+        con.del::<_, ()>(user_cart_key(user_id)).await.unwrap();
+
+        res
+    }
+
+    async fn stock(pool: &sqlx::PgPool, variant_id: i64) -> i32 {
+        sqlx::query_scalar("SELECT quantity FROM variants WHERE id = $1")
+            .bind(variant_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_orders(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let (user_id, res) = order(&pool, 3, 2).await;
+        assert_eq!(res.unwrap().status(), StatusCode::CREATED);
+        assert_eq!(stock(&pool, 3).await, 98);
+
+        // The total is kept at the price it was ordered at:
+        sqlx::query("UPDATE variants SET price = 999 WHERE id = 3")
+            .execute(&pool)
+            .await?;
+        let orders = serde_json::to_value(Order::from_user_id(&pool, user_id).await?).unwrap();
+        assert_eq!(orders[0]["total"], 2 * 175);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_orders_out_of_stock(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let (user_id, res) = order(&pool, 1, 101).await;
+        let (code, _) = res.unwrap_err();
+        assert_eq!(code, StatusCode::CONFLICT);
+
+        assert_eq!(stock(&pool, 1).await, 100);
+        assert!(Order::from_user_id(&pool, user_id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_orders_unavailable(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // Doesn't exist:
+        let (_, res) = order(&pool, 999, 1).await;
+        let (code, body) = res.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["variantId"], 999);
+
+        // Deleted variant:
+        sqlx::query("UPDATE variants SET deleted_at = NOW() WHERE id = 3")
+            .execute(&pool)
+            .await?;
+        let (user_id, res) = order(&pool, 3, 1).await;
+        let (code, _) = res.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(stock(&pool, 3).await, 100);
+        assert!(Order::from_user_id(&pool, user_id).await?.is_empty());
+
+        // Deleted product, the variant itself isn't:
+        sqlx::query("UPDATE inventory SET deleted_at = NOW() WHERE id = 2")
+            .execute(&pool)
+            .await?;
+        let (_, res) = order(&pool, 2, 1).await;
+        let (code, _) = res.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(stock(&pool, 2).await, 100);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("shop"))]
+    async fn test_post_orders_other_users_address(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        // Address 1 is user 1's:
+        let user_id = rand_user_id();
+        let (code, body) = order_to(&pool, user_id, 1, 3, 1).await.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["message"], "address not found");
+
+        let (code, _) = order_to(&pool, user_id, 999, 3, 1).await.unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert_eq!(stock(&pool, 3).await, 100);
+        assert!(Order::from_user_id(&pool, user_id).await?.is_empty());
+
+        // The owner can:
+        let res = order_to(&pool, 1, 1, 3, 1).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        Ok(())
+    }
+}
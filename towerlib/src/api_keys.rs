@@ -0,0 +1,66 @@
+use dblib::users::api_keys::ApiKey;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{types::chrono, PgPool};
+
+use crate::auth::AuthError;
+
+/// Scopes a key can be given, each service's routes say which they need.
+pub const SCOPES: [&str; 4] = [
+    "inventory:write",
+    "orders:read",
+    "orders:write",
+    "users:read",
+];
+
+/// Keys start with this, so they are easy to spot in config and logs.
+const PREFIX: &str = "tpk_";
+
+/// Seconds between writes of a key's `last_used_at`, so verifying a busy
+/// key doesn't write to the database on every request.
+const LAST_USED_INTERVAL: i64 = 60;
+
+fn hash(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+/// Creates a key for scripts and integrations, sent as
+/// `Authorization: ApiKey ...`. Returns the key, which is only shown this
+/// once, along with its details. `scopes` should be from `SCOPES`.
+pub async fn create_api_key(
+    pool: &PgPool,
+    name: &str,
+    scopes: &[String],
+    created_by: i64,
+    expires_at: Option<u64>,
+) -> Result<(String, ApiKey), sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let key = format!("{}{}", PREFIX, secret);
+
+    let expires_at = expires_at.map(|exp| exp as i64);
+    let api_key = ApiKey::new(pool, name, scopes, created_by, expires_at, &hash(&key)).await?;
+
+    Ok((key, api_key))
+}
+
+/// Looks the key up by its hash and records that it was used.
+pub async fn verify_api_key(pool: &PgPool, key: &str) -> Result<ApiKey, AuthError> {
+    let api_key = match ApiKey::from_hash(pool, &hash(key)).await {
+        Ok(k) => k,
+        Err(sqlx::Error::RowNotFound) => return Err(AuthError::Verify),
+        Err(e) => return Err(AuthError::Database(e)),
+    };
+
+    if matches!(api_key.expires_at, Some(exp) if exp <= chrono::Utc::now()) {
+        return Err(AuthError::Expired);
+    }
+
+    api_key
+        .touch(pool, LAST_USED_INTERVAL)
+        .await
+        .map_err(AuthError::Database)?;
+
+    Ok(api_key)
+}
@@ -0,0 +1,630 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dblib::users::api_keys::ApiKey;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    http::{Extensions, HeaderValue},
+    Body, HeaderMap, Request, Response, StatusCode,
+};
+use redis::{AsyncCommands, Client as RedisClient};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::{
+    api_keys::verify_api_key,
+    keys::{KeyError, Verifier},
+};
+
+/// Seconds an access token is valid for, clients should use a refresh token
+/// to get a new one.
+pub const ACCESS_TOKEN_TTL: u64 = 15 * 60;
+
+const UNAUTHORIZED: &str = r#"{"message": "Unauthorized"}"#;
+const INTERNAL_SERVER_ERROR: &str = r#"{"message": "Internal Server Error"}"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a route requires of the caller, see `check_permission`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Public,
+    User,
+    /// A user that has verified their email address.
+    Verified,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub id: i64,
+    pub email: String,
+    pub role: Role,
+    pub verified: bool,
+    pub iat: u64,
+    /// `iat` in microseconds, so revocations can tell apart tokens issued in
+    /// the same second.
+    pub iat_us: u64,
+    pub exp: u64,
+    pub jti: String,
+}
+
+impl Claims {
+    pub fn new(id: i64, email: String, role: Role, verified: bool) -> Self {
+        let iat_us = now_micros();
+        let iat = iat_us / 1_000_000;
+
+        Self {
+            id,
+            email,
+            role,
+            verified,
+            iat,
+            iat_us,
+            exp: iat + ACCESS_TOKEN_TTL,
+            jti: Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Keys(KeyError),
+    Verify,
+    Expired,
+    Revoked,
+    Redis(redis::RedisError),
+    Database(sqlx::Error),
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Microseconds since the unix epoch.
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Checks the signature and expiry, but not whether the token has been
+/// revoked. Use `verify_token` for that.
+pub async fn decode_token(verifier: &Verifier, token: &str) -> Result<Claims, AuthError> {
+    let claims: Claims = verifier.verify(token).await?;
+
+    if claims.exp <= now() {
+        return Err(AuthError::Expired);
+    }
+
+    Ok(claims)
+}
+
+pub async fn verify_token(
+    verifier: &Verifier,
+    redis: &RedisClient,
+    token: &str,
+) -> Result<Claims, AuthError> {
+    let claims = decode_token(verifier, token).await?;
+
+    if is_revoked(redis, &claims).await.map_err(AuthError::Redis)? {
+        return Err(AuthError::Revoked);
+    }
+
+    Ok(claims)
+}
+
+fn revoked_token_key(jti: &str) -> String {
+    let mut key = String::from("revoked:token:");
+    key.push_str(jti);
+    key
+}
+
+fn revoked_user_key(id: i64) -> String {
+    let mut key = String::from("revoked:user:");
+    key.push_str(&id.to_string());
+    key
+}
+
+/// Revokes a single token. The entry only needs to outlive the token.
+pub async fn revoke_token(redis: &RedisClient, claims: &Claims) -> redis::RedisResult<()> {
+    let ttl = claims.exp.saturating_sub(now()).max(1);
+
+    let mut con = redis.get_async_connection().await?;
+    con.set_ex(revoked_token_key(&claims.jti), 1, ttl as usize)
+        .await
+}
+
+/// Revokes every token issued to the user up to now. This is kept to the
+/// microsecond, and tokens issued once this returns are at least a round
+/// trip to redis later, so they can be issued straight after. Tokens issued
+/// before this are all expired after `ACCESS_TOKEN_TTL`, so the entry is too.
+pub async fn revoke_user(redis: &RedisClient, id: i64) -> redis::RedisResult<()> {
+    let revoked_at = now_micros();
+
+    let mut con = redis.get_async_connection().await?;
+    con.set_ex(revoked_user_key(id), revoked_at, ACCESS_TOKEN_TTL as usize)
+        .await
+}
+
+pub async fn is_revoked(redis: &RedisClient, claims: &Claims) -> redis::RedisResult<bool> {
+    let mut con = redis.get_async_connection().await?;
+
+    let (token, user): (Option<u8>, Option<u64>) = redis::pipe()
+        .get(revoked_token_key(&claims.jti))
+        .get(revoked_user_key(claims.id))
+        .query_async(&mut con)
+        .await?;
+
+    Ok(token.is_some() || matches!(user, Some(revoked_at) if claims.iat_us <= revoked_at))
+}
+
+enum Credentials {
+    Bearer(String),
+    ApiKey(String),
+}
+
+fn get_credentials(headers: &HeaderMap) -> Option<Result<Credentials, ()>> {
+    let header = headers.get(AUTHORIZATION)?;
+
+    let header = match header.to_str() {
+        Ok(h) => h,
+        Err(_) => return Some(Err(())),
+    };
+
+    if let Some(t) = header.strip_prefix("Bearer ") {
+        return Some(Ok(Credentials::Bearer(t.to_owned())));
+    }
+    if let Some(k) = header.strip_prefix("ApiKey ") {
+        return Some(Ok(Credentials::ApiKey(k.to_owned())));
+    }
+
+    Some(Err(()))
+}
+
+/// Verifies the bearer token, if there is one, and adds its `Claims` to the
+/// request extensions. Requests without a token, or with an expired or
+/// revoked one, are passed through without claims, handlers that need a user
+/// should use `get_claims`. API keys are added as an
+/// `ApiKey` instead, see `check_access`.
+#[derive(Clone)]
+pub struct Auth<S> {
+    inner: S,
+    redis: RedisClient,
+    verifier: Verifier,
+    api_keys: Option<PgPool>,
+}
+
+impl<S> Auth<S> {
+    pub fn new(inner: S, redis: RedisClient, verifier: Verifier) -> Self {
+        Self {
+            inner,
+            redis,
+            verifier,
+            api_keys: None,
+        }
+    }
+
+    /// Checks API keys against the users database, they are refused
+    /// without it.
+    pub fn with_api_keys(mut self, pool: PgPool) -> Self {
+        self.api_keys = Some(pool);
+        self
+    }
+}
+
+impl<S, B> Service<Request<B>> for Auth<S>
+where
+    S: Service<Request<B>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        // The clone might not be ready, use the service that was polled:
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let redis = self.redis.clone();
+        let verifier = self.verifier.clone();
+        let api_keys = self.api_keys.clone();
+
+        Box::pin(async move {
+            let verified = match get_credentials(req.headers()) {
+                Some(Ok(Credentials::Bearer(token))) => verify_token(&verifier, &redis, &token)
+                    .await
+                    .map(|claims| req.extensions_mut().insert(claims))
+                    .map(|_| ()),
+                Some(Ok(Credentials::ApiKey(key))) => match &api_keys {
+                    Some(pool) => verify_api_key(pool, &key)
+                        .await
+                        .map(|key| req.extensions_mut().insert(key))
+                        .map(|_| ()),
+                    None => Err(AuthError::Verify),
+                },
+                Some(Err(_)) => return Ok(error_response(StatusCode::UNAUTHORIZED)),
+                None => return inner.call(req).await,
+            };
+
+            match verified {
+                Ok(()) => inner.call(req).await,
+                // Clients may send their last token with every request, so
+                // one that has run out shouldn't stop them refreshing or
+                // logging in. Routes that need a user refuse the request
+                // without claims:
+                Err(e @ (AuthError::Expired | AuthError::Revoked)) => {
+                    log::debug!("{:?}", e);
+                    inner.call(req).await
+                }
+                Err(AuthError::Redis(e)) => {
+                    log::error!("{}", e);
+                    Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+                Err(AuthError::Database(e)) => {
+                    log::error!("{}", e);
+                    Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+                Err(AuthError::Keys(e)) => {
+                    log::error!("{:?}", e);
+                    Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+                Err(e) => {
+                    log::debug!("{:?}", e);
+                    Ok(error_response(StatusCode::UNAUTHORIZED))
+                }
+            }
+        })
+    }
+}
+
+/// 401 if the route needs a user and there isn't one, 403 if the user isn't
+/// verified or an admin when the route needs them to be.
+pub fn check_permission(
+    permission: Permission,
+    claims: Option<&Claims>,
+) -> Result<(), (StatusCode, Option<serde_json::Value>)> {
+    let claims = match (permission, claims) {
+        (Permission::Public, _) => return Ok(()),
+        (_, Some(c)) => c,
+        (_, None) => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Some(json!({ "message": "Unauthorized" })),
+            ))
+        }
+    };
+
+    match (permission, claims.role) {
+        (Permission::Admin, Role::User) => Err((
+            StatusCode::FORBIDDEN,
+            Some(json!({ "message": "Forbidden" })),
+        )),
+        (Permission::Verified, _) if !claims.verified => Err((
+            StatusCode::FORBIDDEN,
+            Some(json!({ "message": "Email address has not been verified" })),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks API keys by scope and everything else with `check_permission`.
+/// Keys can call public routes and routes that take one of their scopes,
+/// routes without a `scope` are only for users.
+pub fn check_access(
+    permission: Permission,
+    scope: Option<&str>,
+    extensions: &Extensions,
+) -> Result<(), (StatusCode, Option<serde_json::Value>)> {
+    let key = match extensions.get::<ApiKey>() {
+        Some(k) => k,
+        None => return check_permission(permission, extensions.get()),
+    };
+
+    match (permission, scope) {
+        (Permission::Public, _) => Ok(()),
+        (_, Some(scope)) if key.has_scope(scope) => Ok(()),
+        _ => Err((
+            StatusCode::FORBIDDEN,
+            Some(json!({ "message": "API key does not have the scope for this route" })),
+        )),
+    }
+}
+
+pub(crate) fn error_response(code: StatusCode) -> Response<Body> {
+    let body = match code {
+        StatusCode::UNAUTHORIZED => UNAUTHORIZED,
+        _ => INTERNAL_SERVER_ERROR,
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    response
+}
+
+pub fn get_claims(
+    extensions: &Extensions,
+) -> Result<&Claims, (StatusCode, Option<serde_json::Value>)> {
+    match extensions.get::<Claims>() {
+        Some(c) => Ok(c),
+        // No token was sent, invalid ones are refused in the middleware:
+        None => Err((
+            StatusCode::UNAUTHORIZED,
+            Some(json!({ "message": "Unauthorized" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use hyper::{http::Extensions, service::service_fn, Body, Request, Response, StatusCode};
+    use redis::Client as RedisClient;
+    use tower::Service;
+
+    use super::{
+        check_access, check_permission, decode_token, get_claims, revoke_token, revoke_user,
+        verify_token, Auth, AuthError, Claims, Permission, Role,
+    };
+    use dblib::users::api_keys::ApiKey;
+    use sqlx::types::chrono;
+    use uuid::Uuid;
+
+    use crate::keys::SigningKeys;
+
+    const KEY: &[u8] = include_bytes!("fixtures/jwt-1.pem");
+
+    async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let response = match get_claims(req.extensions()) {
+            Ok(c) => Response::new(Body::from(c.id.to_string())),
+            Err((code, _)) => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = code;
+                response
+            }
+        };
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_decode_token() {
+        let keys = SigningKeys::from_pem(&[KEY]).unwrap();
+        let verifier = keys.verifier();
+
+        let mut claims = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        let token = keys.sign(&claims).unwrap();
+        assert_eq!(decode_token(&verifier, &token).await.unwrap().id, 1);
+
+        claims.exp = claims.iat - 1;
+        let expired = keys.sign(&claims).unwrap();
+        assert!(matches!(
+            decode_token(&verifier, &expired).await,
+            Err(AuthError::Expired)
+        ));
+
+        assert!(matches!(
+            decode_token(&verifier, "invalid").await,
+            Err(AuthError::Verify)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let keys = SigningKeys::from_pem(&[KEY]).unwrap();
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let mut svc = Auth::new(service_fn(handle), redis, keys.verifier());
+
+        let token = keys
+            .sign(&Claims::new(1, "bob@smith.com".into(), Role::User, true))
+            .unwrap();
+        let req = Request::builder()
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .header("Authorization", "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::builder()
+            .header("Authorization", "Basic Ym9iOnBhc3N3b3Jk")
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::builder()
+            .header("Authorization", "ApiKey tpk_invalid")
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Missing tokens get through the middleware, but not get_claims:
+        let req = Request::builder().body(Body::empty()).unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_auth_expired_token() {
+        let keys = SigningKeys::from_pem(&[KEY]).unwrap();
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        // Stands in for a public route:
+        let public = service_fn(|req: Request<Body>| async move {
+            let claims = req.extensions().get::<Claims>().is_some();
+            Ok::<_, Infallible>(Response::new(Body::from(claims.to_string())))
+        });
+        let mut svc = Auth::new(public, redis.clone(), keys.verifier());
+
+        let mut claims = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        claims.exp = claims.iat - 1;
+        let expired = keys.sign(&claims).unwrap();
+        let revoked = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        revoke_token(&redis, &revoked).await.unwrap();
+        let revoked = keys.sign(&revoked).unwrap();
+
+        for token in [expired, revoked] {
+            let req = Request::builder()
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            let res = svc.call(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert_eq!(bytes, "false");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoke_token() {
+        let keys = SigningKeys::from_pem(&[KEY]).unwrap();
+        let verifier = keys.verifier();
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let claims = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        let token = keys.sign(&claims).unwrap();
+        let other = keys
+            .sign(&Claims::new(1, "bob@smith.com".into(), Role::User, true))
+            .unwrap();
+        assert!(verify_token(&verifier, &redis, &token).await.is_ok());
+
+        revoke_token(&redis, &claims).await.unwrap();
+        assert!(matches!(
+            verify_token(&verifier, &redis, &token).await,
+            Err(AuthError::Revoked)
+        ));
+        // Only that token:
+        assert!(verify_token(&verifier, &redis, &other).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_user() {
+        let keys = SigningKeys::from_pem(&[KEY]).unwrap();
+        let verifier = keys.verifier();
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let id = rand::random::<u32>() as i64;
+
+        let old = keys
+            .sign(&Claims::new(id, "bob@smith.com".into(), Role::User, true))
+            .unwrap();
+        revoke_user(&redis, id).await.unwrap();
+        // Most likely in the same second as the revocation:
+        let new = keys
+            .sign(&Claims::new(id, "bob@smith.com".into(), Role::User, true))
+            .unwrap();
+
+        assert!(matches!(
+            verify_token(&verifier, &redis, &old).await,
+            Err(AuthError::Revoked)
+        ));
+        assert_eq!(verify_token(&verifier, &redis, &new).await.unwrap().id, id);
+    }
+
+    #[test]
+    fn test_check_permission() {
+        let user = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        let admin = Claims::new(2, "alice@smith.com".into(), Role::Admin, true);
+
+        assert!(check_permission(Permission::Public, None).is_ok());
+
+        let (code, _) = check_permission(Permission::User, None).unwrap_err();
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        assert!(check_permission(Permission::User, Some(&user)).is_ok());
+        assert!(check_permission(Permission::User, Some(&admin)).is_ok());
+
+        let (code, _) = check_permission(Permission::Admin, None).unwrap_err();
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        let (code, _) = check_permission(Permission::Admin, Some(&user)).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert!(check_permission(Permission::Admin, Some(&admin)).is_ok());
+
+        let unverified = Claims::new(3, "carol@smith.com".into(), Role::User, false);
+        assert!(check_permission(Permission::User, Some(&unverified)).is_ok());
+        assert!(check_permission(Permission::Verified, Some(&user)).is_ok());
+        let (code, _) = check_permission(Permission::Verified, Some(&unverified)).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_check_access() {
+        let mut extensions = Extensions::new();
+        extensions.insert(ApiKey {
+            id: Uuid::new_v4(),
+            name: "warehouse".into(),
+            scopes: vec!["orders:read".into()],
+            created_by: Some(1),
+            expires_at: None,
+            last_used_at: None,
+            created_at: chrono::Utc::now(),
+        });
+
+        assert!(check_access(Permission::Public, None, &extensions).is_ok());
+        assert!(check_access(Permission::User, Some("orders:read"), &extensions).is_ok());
+
+        let (code, _) =
+            check_access(Permission::Admin, Some("orders:write"), &extensions).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        let (code, _) = check_access(Permission::User, None, &extensions).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+
+        // Without a key it's down to the claims:
+        let (code, _) =
+            check_access(Permission::User, Some("orders:read"), &Extensions::new()).unwrap_err();
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,82 @@
+use redis::{aio::Connection, AsyncCommands, Client as RedisClient};
+
+use crate::session::{session_key, SESSION_TTL};
+
+/// Carts are Redis sets of JSON items. Guests have one per session, and the
+/// shop service moves it into the user's own cart once they log in.
+pub fn cart_key(session_id: &str) -> String {
+    let mut key = String::from("cart:");
+    key.push_str(session_id);
+    key
+}
+
+pub fn user_cart_key(user_id: i64) -> String {
+    let mut key = String::from("cart:user:");
+    key.push_str(&user_id.to_string());
+    key
+}
+
+/// The sessions the user has used the cart in while logged in. Their guest
+/// carts are the user's too, eg after logging out.
+fn user_sessions_key(user_id: i64) -> String {
+    let mut key = user_cart_key(user_id);
+    key.push_str(":sessions");
+    key
+}
+
+/// Returns true the first time the session is added. The set expires like a
+/// session, `SESSION_TTL` after the user last used the cart.
+pub async fn add_user_session(
+    con: &mut Connection,
+    user_id: i64,
+    session_id: &str,
+) -> redis::RedisResult<bool> {
+    let key = user_sessions_key(user_id);
+
+    let (added,): (bool,) = redis::pipe()
+        .atomic()
+        .sadd(&key, session_id)
+        .expire(&key, SESSION_TTL)
+        .ignore()
+        .query_async(con)
+        .await?;
+
+    Ok(added)
+}
+
+/// The items in the user's cart as they are stored, for data exports.
+pub async fn get_user_cart(
+    redis: &RedisClient,
+    user_id: i64,
+) -> redis::RedisResult<Vec<serde_json::Value>> {
+    let mut con = redis.get_async_connection().await?;
+
+    let items: Vec<String> = con.smembers(user_cart_key(user_id)).await?;
+
+    Ok(items
+        .iter()
+        .filter_map(|item| match serde_json::from_str(item) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::error!("{}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Deletes the user's cart, and the guest carts and sessions they have used
+/// while logged in.
+pub async fn delete_user_cart(redis: &RedisClient, user_id: i64) -> redis::RedisResult<()> {
+    let mut con = redis.get_async_connection().await?;
+
+    let sessions: Vec<String> = con.smembers(user_sessions_key(user_id)).await?;
+
+    let mut keys = vec![user_cart_key(user_id), user_sessions_key(user_id)];
+    for session_id in &sessions {
+        keys.push(cart_key(session_id));
+        keys.push(session_key(session_id));
+    }
+
+    con.del(keys).await
+}
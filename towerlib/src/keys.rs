@@ -0,0 +1,532 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use hyper::{Client, StatusCode, Uri};
+use jwt::{AlgorithmType, Header, PKeyWithDigest, SignWithKey, Token, VerifyWithStore};
+use openssl::{
+    bn::BigNum,
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::{PKey, Private, Public},
+    rsa::Rsa,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::auth::AuthError;
+
+/// How long a fetched key set is used before it is fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Tokens signed with a key that isn't in the set cause a fetch, but no more
+/// often than this.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+static SIGNING_KEYS: OnceLock<SigningKeys> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(std::io::Error),
+    OpenSsl(ErrorStack),
+    Jwt(jwt::Error),
+    Http(hyper::Error),
+    Status(StatusCode),
+    Json(serde_json::Error),
+    NoKeys,
+    /// A JWK's `n` or `e` isn't base64url.
+    Base64(base64::DecodeError),
+}
+
+/// A public key in the format served at `/.well-known/jwks.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub use_: Option<String>,
+    pub alg: Option<String>,
+    pub kid: String,
+    pub n: String,
+    pub e: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// A `Jwks` with its keys left unparsed, so a bad one can be skipped.
+#[derive(Deserialize)]
+struct UncheckedJwks {
+    keys: Vec<serde_json::Value>,
+}
+
+struct SigningKey {
+    kid: String,
+    key: PKeyWithDigest<Private>,
+}
+
+/// RS256 keys for signing tokens. The first key signs, the others are only
+/// published so tokens they signed still verify while they are rotated out.
+pub struct SigningKeys {
+    keys: Vec<SigningKey>,
+    public: BTreeMap<String, PKeyWithDigest<Public>>,
+}
+
+impl SigningKeys {
+    /// Takes PEM encoded RSA private keys, the first is used to sign.
+    pub fn from_pem(pems: &[&[u8]]) -> Result<Self, KeyError> {
+        if pems.is_empty() {
+            return Err(KeyError::NoKeys);
+        }
+
+        let mut keys = Vec::new();
+        let mut public = BTreeMap::new();
+
+        for pem in pems {
+            let key = PKey::private_key_from_pem(pem).map_err(KeyError::OpenSsl)?;
+            let rsa = key.rsa().map_err(KeyError::OpenSsl)?;
+            let jwk = rsa_jwk(&rsa.n().to_vec(), &rsa.e().to_vec());
+
+            let der = key.public_key_to_der().map_err(KeyError::OpenSsl)?;
+            let public_key = PKey::public_key_from_der(&der).map_err(KeyError::OpenSsl)?;
+
+            public.insert(jwk.kid.to_owned(), with_digest(public_key));
+            keys.push(SigningKey {
+                kid: jwk.kid,
+                key: with_digest(key),
+            });
+        }
+
+        Ok(Self { keys, public })
+    }
+
+    /// Reads the PEM files listed in `JWT_PRIVATE_KEYS`, separated by commas.
+    /// To rotate, add the new key to the front and remove the old one once
+    /// the tokens it signed have expired.
+    pub fn from_env() -> Result<Self, KeyError> {
+        let paths = env::var("JWT_PRIVATE_KEYS").expect("JWT_PRIVATE_KEYS must be set");
+
+        let pems = paths
+            .split(',')
+            .map(|p| fs::read(p.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(KeyError::Io)?;
+        let pems: Vec<&[u8]> = pems.iter().map(|p| p.as_slice()).collect();
+
+        Self::from_pem(&pems)
+    }
+
+    /// Signs the claims with the first key, its `kid` is set in the header.
+    pub fn sign<C: Serialize>(&self, claims: &C) -> Result<String, KeyError> {
+        let signing = &self.keys[0];
+
+        let header = Header {
+            algorithm: AlgorithmType::Rs256,
+            key_id: Some(signing.kid.to_owned()),
+            ..Default::default()
+        };
+
+        let token = Token::new(header, claims)
+            .sign_with_key(&signing.key)
+            .map_err(KeyError::Jwt)?;
+
+        Ok(token.as_str().to_owned())
+    }
+
+    /// Checks the signature against the public half of these keys.
+    pub fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, jwt::Error> {
+        token.verify_with_store(&self.public)
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        let keys = self
+            .keys
+            .iter()
+            .filter_map(|k| k.key.key.rsa().ok())
+            .map(|rsa| rsa_jwk(&rsa.n().to_vec(), &rsa.e().to_vec()))
+            .collect();
+
+        Jwks { keys }
+    }
+
+    /// A verifier for the tokens these keys sign, for the service that holds
+    /// them. Other services should use `Verifier::from_url`.
+    pub fn verifier(&self) -> Verifier {
+        let keys = self
+            .public
+            .iter()
+            .map(|(kid, k)| (kid.to_owned(), with_digest(k.key.clone())))
+            .collect();
+
+        Verifier {
+            cached: Arc::new(RwLock::new(Cached {
+                keys,
+                fetched_at: None,
+            })),
+            refreshing: Arc::new(Mutex::new(())),
+            url: None,
+        }
+    }
+}
+
+/// Sets the keys `signing_keys` returns instead of reading them from the
+/// environment, eg in tests. Gives the keys back if they were already set.
+pub fn set_signing_keys(keys: SigningKeys) -> Result<(), SigningKeys> {
+    SIGNING_KEYS.set(keys)
+}
+
+/// The keys from `SigningKeys::from_env`, loaded on first use.
+pub fn signing_keys() -> &'static SigningKeys {
+    SIGNING_KEYS.get_or_init(|| match SigningKeys::from_env() {
+        Ok(keys) => keys,
+        Err(e) => panic!("Could not load JWT_PRIVATE_KEYS: {:?}", e),
+    })
+}
+
+struct Cached {
+    keys: BTreeMap<String, PKeyWithDigest<Public>>,
+    fetched_at: Option<Instant>,
+}
+
+/// Public keys for verifying tokens, by `kid`. Keys from a URL are cached
+/// for `JWKS_TTL` and fetched again early if a token has an unknown `kid`,
+/// which is how new keys are picked up after a rotation. Only one request
+/// fetches at a time, the others wait for it and use what it fetched.
+#[derive(Clone)]
+pub struct Verifier {
+    cached: Arc<RwLock<Cached>>,
+    refreshing: Arc<Mutex<()>>,
+    url: Option<Uri>,
+}
+
+impl Verifier {
+    /// The key set is fetched on first use. Only plain HTTP is supported, the
+    /// URL should be on the internal network.
+    pub fn from_url(url: Uri) -> Self {
+        Self {
+            cached: Arc::new(RwLock::new(Cached {
+                keys: BTreeMap::new(),
+                fetched_at: None,
+            })),
+            refreshing: Arc::new(Mutex::new(())),
+            url: Some(url),
+        }
+    }
+
+    /// Checks the signature only, see `auth::decode_token` for access tokens.
+    /// Fails with `AuthError::Keys` only if there are no keys at all.
+    pub async fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, AuthError> {
+        if let Err(e) = self.refresh_if_older_than(JWKS_TTL).await {
+            if self.cached.read().unwrap().keys.is_empty() {
+                return Err(AuthError::Keys(e));
+            }
+            // Keep using the keys there are:
+            log::error!("{:?}", e);
+        }
+
+        match self.verify_cached(token) {
+            Err(jwt::Error::NoKeyWithKeyId(kid)) if self.is_older_than(JWKS_MIN_REFRESH) => {
+                log::debug!("Fetching keys for unknown kid {}", kid);
+                // The token can't be verified either way, so it is a 401:
+                if let Err(e) = self.refresh_if_older_than(JWKS_MIN_REFRESH).await {
+                    log::error!("{:?}", e);
+                }
+                self.verify_cached(token)
+            }
+            res => res,
+        }
+        .map_err(|e| {
+            log::debug!("{:?}", e);
+            AuthError::Verify
+        })
+    }
+
+    fn verify_cached<C: DeserializeOwned>(&self, token: &str) -> Result<C, jwt::Error> {
+        token.verify_with_store(&self.cached.read().unwrap().keys)
+    }
+
+    /// Whether keys from a URL were fetched longer ago than `age`, or never.
+    fn is_older_than(&self, age: Duration) -> bool {
+        let fetched_at = self.cached.read().unwrap().fetched_at;
+        let fresh = matches!(fetched_at, Some(t) if t.elapsed() <= age);
+        self.url.is_some() && !fresh
+    }
+
+    /// Fetches the keys if they are older than `age`, unless another request
+    /// fetched them while this one waited its turn.
+    async fn refresh_if_older_than(&self, age: Duration) -> Result<(), KeyError> {
+        if !self.is_older_than(age) {
+            return Ok(());
+        }
+
+        let _refreshing = self.refreshing.lock().await;
+        if !self.is_older_than(age) {
+            return Ok(());
+        }
+
+        self.refresh().await
+    }
+
+    async fn refresh(&self) -> Result<(), KeyError> {
+        let url = match &self.url {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+
+        let res = Client::new().get(url.clone()).await;
+
+        // Whether it worked or not, don't fetch again straight away:
+        self.cached.write().unwrap().fetched_at = Some(Instant::now());
+
+        let res = res.map_err(KeyError::Http)?;
+        if !res.status().is_success() {
+            return Err(KeyError::Status(res.status()));
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(KeyError::Http)?;
+        let jwks: UncheckedJwks = serde_json::from_slice(&bytes).map_err(KeyError::Json)?;
+
+        let keys: BTreeMap<_, _> = jwks
+            .keys
+            .into_iter()
+            .filter_map(|k| match serde_json::from_value::<Jwk>(k) {
+                Ok(k) => Some(k),
+                Err(e) => {
+                    log::warn!("Skipping invalid JWK: {}", e);
+                    None
+                }
+            })
+            .filter(|k| k.kty == "RSA" && matches!(k.alg.as_deref(), None | Some("RS256")))
+            .filter_map(|k| match public_key(&k) {
+                Ok(key) => Some((k.kid, with_digest(key))),
+                Err(e) => {
+                    log::warn!("Skipping invalid JWK {}: {:?}", k.kid, e);
+                    None
+                }
+            })
+            .collect();
+
+        if keys.is_empty() {
+            return Err(KeyError::NoKeys);
+        }
+
+        self.cached.write().unwrap().keys = keys;
+
+        Ok(())
+    }
+}
+
+fn with_digest<T>(key: PKey<T>) -> PKeyWithDigest<T> {
+    PKeyWithDigest {
+        digest: MessageDigest::sha256(),
+        key,
+    }
+}
+
+fn public_key(jwk: &Jwk) -> Result<PKey<Public>, KeyError> {
+    let decode = |s: &str| base64::decode_config(s, base64::URL_SAFE_NO_PAD);
+
+    let n = decode(&jwk.n).map_err(KeyError::Base64)?;
+    let e = decode(&jwk.e).map_err(KeyError::Base64)?;
+    let rsa = Rsa::from_public_components(
+        BigNum::from_slice(&n).map_err(KeyError::OpenSsl)?,
+        BigNum::from_slice(&e).map_err(KeyError::OpenSsl)?,
+    )
+    .map_err(KeyError::OpenSsl)?;
+
+    PKey::from_rsa(rsa).map_err(KeyError::OpenSsl)
+}
+
+/// The `kid` is the key's RFC 7638 thumbprint, so it is the same wherever
+/// the key is loaded.
+fn rsa_jwk(n: &[u8], e: &[u8]) -> Jwk {
+    let n = base64::encode_config(n, base64::URL_SAFE_NO_PAD);
+    let e = base64::encode_config(e, base64::URL_SAFE_NO_PAD);
+
+    let thumbprint = Sha256::digest(format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n));
+
+    Jwk {
+        kty: "RSA".into(),
+        use_: Some("sig".into()),
+        alg: Some("RS256".into()),
+        kid: base64::encode_config(thumbprint, base64::URL_SAFE_NO_PAD),
+        n,
+        e,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::{SigningKeys, Verifier, JWKS_MIN_REFRESH};
+    use crate::auth::AuthError;
+
+    const KEY_1: &[u8] = include_bytes!("fixtures/jwt-1.pem");
+    const KEY_2: &[u8] = include_bytes!("fixtures/jwt-2.pem");
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestClaims {
+        id: i64,
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = SigningKeys::from_pem(&[KEY_1]).unwrap();
+        let rotated = SigningKeys::from_pem(&[KEY_2, KEY_1]).unwrap();
+
+        let token = old.sign(&TestClaims { id: 1 }).unwrap();
+
+        // Tokens from the old key still verify after the new one is added:
+        assert_eq!(
+            rotated.verify::<TestClaims>(&token).unwrap(),
+            TestClaims { id: 1 }
+        );
+        assert_eq!(rotated.jwks().keys.len(), 2);
+
+        let token = rotated.sign(&TestClaims { id: 2 }).unwrap();
+        assert!(old.verify::<TestClaims>(&token).is_err());
+    }
+
+    /// Serves `jwks` while `up` is set, 500s otherwise. `hits` counts the
+    /// requests. Returns the URL.
+    fn serve_jwks(jwks: String, up: Arc<AtomicBool>, hits: Arc<AtomicUsize>) -> String {
+        let make_service = make_service_fn(move |_| {
+            let jwks = jwks.clone();
+            let up = up.clone();
+            let hits = hits.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    let mut res = Response::new(Body::from(jwks.clone()));
+                    if !up.load(Ordering::SeqCst) {
+                        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    async move { Ok::<_, Infallible>(res) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}/.well-known/jwks.json", server.local_addr());
+        tokio::spawn(server);
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_verifier_from_url() {
+        let keys = SigningKeys::from_pem(&[KEY_1]).unwrap();
+        let jwks = serde_json::to_string(&keys.jwks()).unwrap();
+        let url = serve_jwks(jwks, Arc::new(AtomicBool::new(true)), Default::default());
+
+        let verifier = Verifier::from_url(url.parse().unwrap());
+
+        let token = keys.sign(&TestClaims { id: 1 }).unwrap();
+        assert_eq!(
+            verifier.verify::<TestClaims>(&token).await.unwrap(),
+            TestClaims { id: 1 }
+        );
+
+        let other = SigningKeys::from_pem(&[KEY_2]).unwrap();
+        let token = other.sign(&TestClaims { id: 1 }).unwrap();
+        assert!(matches!(
+            verifier.verify::<TestClaims>(&token).await,
+            Err(AuthError::Verify)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verifier_skips_bad_keys() {
+        let keys = SigningKeys::from_pem(&[KEY_1]).unwrap();
+        let mut jwks = serde_json::to_value(keys.jwks()).unwrap();
+        let good = jwks["keys"][0].clone();
+        jwks["keys"] = json!([
+            { "kty": "RSA", "kid": "no-modulus", "e": "AQAB" },
+            { "kty": "RSA", "kid": "not-base64", "n": "!!!", "e": "AQAB" },
+            good,
+        ]);
+        let url = serve_jwks(
+            jwks.to_string(),
+            Arc::new(AtomicBool::new(true)),
+            Default::default(),
+        );
+
+        let verifier = Verifier::from_url(url.parse().unwrap());
+        let token = keys.sign(&TestClaims { id: 1 }).unwrap();
+        assert_eq!(
+            verifier.verify::<TestClaims>(&token).await.unwrap(),
+            TestClaims { id: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verifier_fetches_once() {
+        let keys = SigningKeys::from_pem(&[KEY_1]).unwrap();
+        let jwks = serde_json::to_string(&keys.jwks()).unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = serve_jwks(jwks, Arc::new(AtomicBool::new(true)), hits.clone());
+
+        let verifier = Verifier::from_url(url.parse().unwrap());
+        let token = keys.sign(&TestClaims { id: 1 }).unwrap();
+
+        let verifies: Vec<_> = (0..10)
+            .map(|_| {
+                let verifier = verifier.clone();
+                let token = token.clone();
+                tokio::spawn(async move { verifier.verify::<TestClaims>(&token).await })
+            })
+            .collect();
+        for verify in verifies {
+            assert!(verify.await.unwrap().is_ok());
+        }
+
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_verifier_unknown_kid_while_down() {
+        let keys = SigningKeys::from_pem(&[KEY_1]).unwrap();
+        let jwks = serde_json::to_string(&keys.jwks()).unwrap();
+        let up = Arc::new(AtomicBool::new(true));
+        let url = serve_jwks(jwks, up.clone(), Default::default());
+
+        let verifier = Verifier::from_url(url.parse().unwrap());
+        let token = keys.sign(&TestClaims { id: 1 }).unwrap();
+        assert!(verifier.verify::<TestClaims>(&token).await.is_ok());
+
+        // Past JWKS_MIN_REFRESH but not JWKS_TTL, so only an unknown kid
+        // fetches:
+        up.store(false, Ordering::SeqCst);
+        verifier.cached.write().unwrap().fetched_at =
+            Some(Instant::now() - JWKS_MIN_REFRESH - Duration::from_secs(1));
+
+        let other = SigningKeys::from_pem(&[KEY_2]).unwrap();
+        let token = other.sign(&TestClaims { id: 1 }).unwrap();
+        assert!(matches!(
+            verifier.verify::<TestClaims>(&token).await,
+            Err(AuthError::Verify)
+        ));
+    }
+}
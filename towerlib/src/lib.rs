@@ -1,2 +1,6 @@
+pub mod api_keys;
+pub mod auth;
+pub mod cart;
+pub mod keys;
 pub mod logging;
 pub mod session;
@@ -1,65 +1,424 @@
-use hyper::{http::HeaderValue, HeaderMap, Request, StatusCode};
-use rand::Rng;
+use std::{
+    env,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    header::{COOKIE, SET_COOKIE},
+    http::HeaderValue,
+    Body, HeaderMap, Request, Response, StatusCode,
+};
+use rand::RngCore;
+use redis::{aio::Connection, AsyncCommands, Client as RedisClient};
 use tower::Service;
 
+use crate::auth::error_response;
+
 pub const SESSION_ID: &str = "Session-ID";
 
+/// Name of the cookie the session ID is sent in, see `SessionCookie`.
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Seconds a session lasts without being used, each request starts it again.
+pub const SESSION_TTL: usize = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Always sent with `Secure`, browsers drop the cookie otherwise.
+    None,
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// Attributes of the session cookie. The default is `HttpOnly`, `Secure` and
+/// `SameSite=Lax`, lasting as long as the session. `SameSite=None` is sent
+/// `Secure` whatever `secure` is.
+#[derive(Debug, Clone)]
+pub struct SessionCookie {
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Seconds, each response starts it again like the session's TTL.
+    pub max_age: usize,
+}
+
+impl Default for SessionCookie {
+    fn default() -> Self {
+        Self {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            max_age: SESSION_TTL,
+        }
+    }
+}
+
+impl SessionCookie {
+    /// The default, with `SESSION_COOKIE_SECURE` (`true` or `false`) and
+    /// `SESSION_COOKIE_SAME_SITE` (`strict`, `lax` or `none`) if they're set.
+    /// Plain HTTP dev setups need `SESSION_COOKIE_SECURE=false`, browsers
+    /// don't keep secure cookies from them.
+    pub fn from_env() -> Self {
+        let mut cookie = Self::default();
+
+        if let Some(secure) = env::var("SESSION_COOKIE_SECURE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            cookie.secure = secure;
+        }
+        if let Some(same_site) = env::var("SESSION_COOKIE_SAME_SITE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            cookie.same_site = same_site;
+        }
+
+        cookie
+    }
+
+    fn to_header(&self, session_id: &str) -> HeaderValue {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; SameSite={}",
+            SESSION_COOKIE,
+            session_id,
+            self.max_age,
+            self.same_site.as_str()
+        );
+
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure || self.same_site == SameSite::None {
+            cookie.push_str("; Secure");
+        }
+
+        HeaderValue::from_str(&cookie).unwrap()
+    }
+}
+
+/// Makes sure every request has a valid session. Sessions are stored in
+/// Redis, a missing, forged or expired ID is replaced with a new one, which
+/// is sent back in the response headers. New IDs are only stored once a
+/// handler uses them with `save_session`, so requests that never do, like
+/// crawlers', don't leave a key behind. The ID is read from the `Session-ID`
+/// header or, with `with_cookie`, the session cookie.
 #[derive(Clone)]
 pub struct Session<S> {
     inner: S,
+    redis: RedisClient,
+    cookie: Option<SessionCookie>,
 }
 
 impl<S> Session<S> {
-    pub fn new(inner: S) -> Self {
-        Self { inner }
+    pub fn new(inner: S, redis: RedisClient) -> Self {
+        Self {
+            inner,
+            redis,
+            cookie: None,
+        }
+    }
+
+    /// Also sends the session ID in a cookie, so browsers keep it without
+    /// any JavaScript.
+    pub fn with_cookie(mut self, cookie: SessionCookie) -> Self {
+        self.cookie = Some(cookie);
+        self
     }
 }
 
 impl<S, B> Service<Request<B>> for Session<S>
 where
-    S: Service<Request<B>>,
+    S: Service<Request<B>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(
-        &mut self,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, mut req: Request<B>) -> Self::Future {
-        let session_id = req.headers().get(SESSION_ID);
-
-        if let Some(id) = session_id {
-            // TODO: verify ID, set future type, if invalid return error
-            log::info!("{:?}", id);
-        } else {
-            let session_id = HeaderValue::from_str(&gen_session()).unwrap();
-            req.headers_mut().insert(SESSION_ID, session_id);
-        };
+        // The clone might not be ready, use the service that was polled:
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let redis = self.redis.clone();
+        let cookie = self.cookie.clone();
 
-        self.inner.call(req)
+        Box::pin(async move {
+            let session_id = get_session(req.headers()).ok().map(|id| id.to_owned());
+
+            let valid = match &session_id {
+                Some(id) => touch_session(&redis, id).await,
+                None => Ok(false),
+            };
+
+            let issued = match valid {
+                Ok(true) => None,
+                Ok(false) => Some(HeaderValue::from_str(&gen_session_id()).unwrap()),
+                Err(e) => {
+                    log::error!("{}", e);
+                    return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR));
+                }
+            };
+
+            // The header is read before the cookie, so handlers get the new
+            // ID even if an old cookie was sent:
+            if let Some(id) = &issued {
+                req.headers_mut().insert(SESSION_ID, id.clone());
+            }
+
+            let session_id = match &issued {
+                Some(id) => id.to_str().unwrap().to_owned(),
+                // Only valid IDs aren't replaced:
+                None => session_id.unwrap_or_default(),
+            };
+
+            let mut response = inner.call(req).await?;
+
+            if let Some(id) = issued {
+                response.headers_mut().insert(SESSION_ID, id);
+            }
+
+            if let Some(cookie) = cookie {
+                response
+                    .headers_mut()
+                    .append(SET_COOKIE, cookie.to_header(&session_id));
+            }
+
+            Ok(response)
+        })
     }
 }
 
-pub fn gen_session() -> String {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(0..100_000).to_string()
+pub(crate) fn session_key(session_id: &str) -> String {
+    let mut key = String::from("session:");
+    key.push_str(session_id);
+    key
+}
+
+/// 128 random bits in hex. The session isn't stored until `save_session`.
+fn gen_session_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Starts a new session and stores it straight away.
+pub async fn gen_session(redis: &RedisClient) -> redis::RedisResult<String> {
+    let session_id = gen_session_id();
+
+    let mut con = redis.get_async_connection().await?;
+    save_session(&mut con, &session_id).await?;
+
+    Ok(session_id)
+}
+
+/// Stores the session, if it isn't already, for handlers that keep
+/// something against it. Only use IDs from `get_session`, the middleware has
+/// either found them in Redis or just issued them.
+pub async fn save_session(con: &mut Connection, session_id: &str) -> redis::RedisResult<()> {
+    con.set_ex(session_key(session_id), 1, SESSION_TTL).await
+}
+
+/// Extends the session if it exists. Returns false for IDs that were never
+/// issued or have expired.
+pub async fn touch_session(redis: &RedisClient, session_id: &str) -> redis::RedisResult<bool> {
+    // Don't make a round trip for IDs that can't be ours:
+    let well_formed = session_id.len() == 32 && session_id.bytes().all(|b| b.is_ascii_hexdigit());
+    if !well_formed {
+        return Ok(false);
+    }
+
+    let mut con = redis.get_async_connection().await?;
+    con.expire(session_key(session_id), SESSION_TTL).await
 }
 
+/// Reads the session ID from the `Session-ID` header, or the session cookie
+/// if there is no header.
 pub fn get_session(headers: &HeaderMap) -> Result<&str, (StatusCode, Option<serde_json::Value>)> {
     let session = match headers.get(SESSION_ID) {
         Some(s) => s.to_str().map_err(|e| {
             log::error!("{}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, None)
         })?,
-        // Theres was a problem assigning a session ID
-        // in the middleware:
-        None => Err((StatusCode::INTERNAL_SERVER_ERROR, None))?,
+        None => match get_cookie(headers, SESSION_COOKIE) {
+            Some(s) => s,
+            // Theres was a problem assigning a session ID
+            // in the middleware:
+            None => Err((StatusCode::INTERNAL_SERVER_ERROR, None))?,
+        },
     };
 
     Ok(session)
 }
+
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use hyper::{
+        header::{COOKIE, SET_COOKIE},
+        service::service_fn,
+        Body, Request, Response,
+    };
+    use redis::{AsyncCommands, Client as RedisClient};
+    use tower::Service;
+
+    use super::{
+        gen_session, get_session, save_session, session_key, SameSite, Session, SessionCookie,
+        SESSION_ID,
+    };
+
+    async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let session_id = get_session(req.headers()).unwrap().to_owned();
+        Ok(Response::new(Body::from(session_id)))
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let mut svc = Session::new(service_fn(handle), redis.clone());
+
+        // Valid IDs are passed through:
+        let session_id = gen_session(&redis).await.unwrap();
+        let req = Request::builder()
+            .header(SESSION_ID, &session_id)
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert!(res.headers().get(SESSION_ID).is_none());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, session_id.as_bytes());
+
+        // Guessed IDs get a new one:
+        for forged in ["12345", "0123456789abcdef0123456789abcdef"] {
+            let req = Request::builder()
+                .header(SESSION_ID, forged)
+                .body(Body::empty())
+                .unwrap();
+            let res = svc.call(req).await.unwrap();
+            let issued = res.headers().get(SESSION_ID).unwrap().to_owned();
+            assert_ne!(issued, forged);
+            assert_eq!(issued.len(), 32);
+
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert_eq!(body, issued.as_bytes());
+        }
+
+        let req = Request::builder().body(Body::empty()).unwrap();
+        let res = svc.call(req).await.unwrap();
+        let issued = res.headers().get(SESSION_ID).unwrap().to_str().unwrap();
+
+        // New sessions aren't stored until they are used:
+        let mut con = redis.get_async_connection().await.unwrap();
+        let exists: bool = con.exists(session_key(issued)).await.unwrap();
+        assert!(!exists);
+
+        save_session(&mut con, issued).await.unwrap();
+        let req = Request::builder()
+            .header(SESSION_ID, issued)
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert!(res.headers().get(SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_cookie() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let cookie = SessionCookie {
+            same_site: SameSite::Strict,
+            max_age: 60,
+            ..Default::default()
+        };
+        let mut svc = Session::new(service_fn(handle), redis.clone()).with_cookie(cookie);
+
+        let req = Request::builder().body(Body::empty()).unwrap();
+        let res = svc.call(req).await.unwrap();
+        let issued = res.headers().get(SESSION_ID).unwrap().to_str().unwrap();
+        let set_cookie = res.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+        assert_eq!(
+            set_cookie,
+            format!(
+                "session_id={}; Path=/; Max-Age=60; SameSite=Strict; HttpOnly; Secure",
+                issued
+            )
+        );
+
+        // The cookie is enough to keep the session:
+        let session_id = gen_session(&redis).await.unwrap();
+        let req = Request::builder()
+            .header(COOKIE, format!("theme=dark; session_id={}", session_id))
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert!(res.headers().get(SESSION_ID).is_none());
+        assert!(res.headers().get(SET_COOKIE).is_some());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, session_id.as_bytes());
+    }
+
+    #[test]
+    fn test_same_site_none_is_secure() {
+        let cookie = SessionCookie {
+            secure: false,
+            same_site: SameSite::None,
+            max_age: 60,
+            ..Default::default()
+        };
+        assert_eq!(
+            cookie.to_header("abc"),
+            "session_id=abc; Path=/; Max-Age=60; SameSite=None; HttpOnly; Secure"
+        );
+
+        let cookie = SessionCookie {
+            secure: false,
+            max_age: 60,
+            ..Default::default()
+        };
+        assert_eq!(
+            cookie.to_header("abc"),
+            "session_id=abc; Path=/; Max-Age=60; SameSite=Lax; HttpOnly"
+        );
+    }
+}
@@ -0,0 +1,606 @@
+use apilib::{
+    set_response, set_response_v2,
+    validate::{validate, Validate, Validator},
+};
+use dblib::users::{
+    api_keys::ApiKey,
+    auth_events::{AuthEvent, Event},
+    roles::Roles,
+    users::User,
+};
+use hyper::{http::Extensions, Body, Response, StatusCode};
+use query::UrlQuery;
+use redis::Client as RedisClient;
+use serde::Deserialize;
+use sqlx::{Either, PgPool};
+use towerlib::{
+    api_keys::{create_api_key, SCOPES},
+    auth::{get_claims, now, revoke_user, Role},
+};
+use uuid::Uuid;
+
+use crate::{
+    audit::audit, throttle::Throttle, two_factor_on, unauthorized, MAX_EMAIL_LENGTH,
+    MAX_NAME_LENGTH,
+};
+
+#[derive(Deserialize)]
+struct RolesRequest {
+    #[serde(rename(deserialize = "userId"))]
+    user_id: i64,
+    role: Role,
+}
+
+impl Validate for RolesRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.positive("userId", self.user_id);
+    }
+}
+
+pub async fn post_roles(
+    pool: &PgPool,
+    redis: &RedisClient,
+    body: &mut Body,
+    response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: RolesRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    if r.role == Role::Admin && !two_factor_on(pool, r.user_id).await? {
+        return Ok(set_response(
+            response,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Some(r#"{"message": "User needs two-factor authentication on to be an admin"}"#),
+        ));
+    }
+
+    match Roles::grant(pool, r.user_id, r.role.as_str()).await {
+        Ok(_) => (),
+        // No user with that ID:
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => {
+            return Ok(set_response(
+                response,
+                StatusCode::NOT_FOUND,
+                Some(r#"{"message": "User not found"}"#),
+            ));
+        }
+        Err(e) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    refresh_role(redis, r.user_id, response).await
+}
+
+pub async fn delete_roles(
+    pool: &PgPool,
+    redis: &RedisClient,
+    body: &mut Body,
+    response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: RolesRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    Roles::revoke(pool, r.user_id, r.role.as_str())
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    refresh_role(redis, r.user_id, response).await
+}
+
+/// Revokes the user's access tokens so that the next refresh picks up their
+/// new role.
+async fn refresh_role(
+    redis: &RedisClient,
+    user_id: i64,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    revoke_user(redis, user_id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct UnlockRequest {
+    email: String,
+}
+
+impl Validate for UnlockRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email)
+            .max_length("email", &self.email, MAX_EMAIL_LENGTH);
+    }
+}
+
+pub async fn post_unlock(
+    redis: &RedisClient,
+    body: &mut Body,
+    response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: UnlockRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let unlocked = Throttle::from_env()
+        .unlock(redis, &r.email)
+        .await
+        .map_err(|e| {
+            log::error!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if !unlocked {
+        return Ok(set_response(
+            response,
+            StatusCode::NOT_FOUND,
+            Some(r#"{"message": "Account is not locked"}"#),
+        ));
+    }
+
+    log::info!("Unlocked {}", r.email);
+
+    Ok(set_response(
+        response,
+        StatusCode::OK,
+        Some(r#"{"message": "success"}"#),
+    ))
+}
+
+/// Lets support find customers, eg `?email=eq-bob@smith.com` or
+/// `?createdAt=gt-2023-01-01T00:00:00Z&limit=10&offset=0`.
+pub async fn get_users(
+    pool: &PgPool,
+    query: Option<&str>,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let query = query.unwrap_or("");
+    let parsed = match UrlQuery::new(query, ["id", "email", "firstName", "lastName", "createdAt"]) {
+        Ok(p) => p,
+        Err(e) => {
+            log::debug!("{:?}", e);
+            return Ok(set_response(
+                response,
+                StatusCode::BAD_REQUEST,
+                Some(r#"{"message": "invalid query"}"#),
+            ));
+        }
+    };
+
+    if let Err(e) = parsed.check_limit_and_offset() {
+        return Ok(set_response_v2(
+            response,
+            (
+                StatusCode::BAD_REQUEST,
+                Some(serde_json::json!({ "message": e })),
+            ),
+        ));
+    }
+
+    let users = match User::get(pool, parsed).await {
+        Ok(u) => u,
+        Err(Either::Right(e)) => {
+            log::debug!("{}", e);
+            return Ok(set_response(response, StatusCode::BAD_REQUEST, None));
+        }
+        Err(Either::Left(e)) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let res = serde_json::to_string(&users).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+/// For incident response, eg `?userId=eq-1` or `?event=eq-login_failed&ip=eq-10.0.0.1`.
+pub async fn get_auth_events(
+    pool: &PgPool,
+    query: Option<&str>,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let query = query.unwrap_or("");
+    let parsed = match UrlQuery::new(query, ["id", "userId", "event", "email", "ip", "createdAt"]) {
+        Ok(p) => p,
+        Err(e) => {
+            log::debug!("{:?}", e);
+            return Ok(set_response(
+                response,
+                StatusCode::BAD_REQUEST,
+                Some(r#"{"message": "invalid query"}"#),
+            ));
+        }
+    };
+
+    if let Err(e) = parsed.check_limit_and_offset() {
+        return Ok(set_response_v2(
+            response,
+            (
+                StatusCode::BAD_REQUEST,
+                Some(serde_json::json!({ "message": e })),
+            ),
+        ));
+    }
+
+    let events = match AuthEvent::get(pool, parsed).await {
+        Ok(e) => e,
+        Err(Either::Right(e)) => {
+            log::debug!("{}", e);
+            return Ok(set_response(response, StatusCode::BAD_REQUEST, None));
+        }
+        Err(Either::Left(e)) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let res = serde_json::to_string(&events).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+pub async fn get_api_keys(
+    pool: &PgPool,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let keys = ApiKey::list(pool).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let res = serde_json::to_string(&keys).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+    /// Seconds since the unix epoch, keys without one don't expire.
+    #[serde(rename(deserialize = "expiresAt"))]
+    expires_at: Option<u64>,
+}
+
+impl Validate for ApiKeyRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name)
+            .max_length("name", &self.name, MAX_NAME_LENGTH);
+        if self.scopes.is_empty() {
+            v.error("scopes", "required", "scopes is required".into());
+        }
+        for scope in &self.scopes {
+            v.one_of("scopes", scope, &SCOPES);
+        }
+        if matches!(self.expires_at, Some(exp) if exp <= now()) {
+            v.error(
+                "expiresAt",
+                "out_of_range",
+                "expiresAt must be in the future".into(),
+            );
+        }
+    }
+}
+
+/// The key is only in this response, only its hash is kept.
+pub async fn post_api_keys(
+    pool: &PgPool,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: ApiKeyRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let (key, api_key) = create_api_key(pool, &r.name, &r.scopes, claims.id, r.expires_at)
+        .await
+        .map_err(|e| {
+            log::error!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    audit(&mut response, Event::ApiKeyCreated, Some(claims.id), None);
+
+    let mut res = serde_json::to_value(&api_key).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    res["key"] = serde_json::Value::String(key);
+
+    *response.status_mut() = StatusCode::CREATED;
+    *response.body_mut() = Body::from(res.to_string());
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct DeleteApiKeyRequest {
+    id: String,
+}
+
+impl Validate for DeleteApiKeyRequest {
+    fn validate(&self, v: &mut Validator) {
+        if Uuid::parse_str(&self.id).is_err() {
+            v.error("id", "invalid_id", "id is not a valid API key ID".into());
+        }
+    }
+}
+
+pub async fn delete_api_keys(
+    pool: &PgPool,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: DeleteApiKeyRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    // Checked by validate:
+    let id = Uuid::parse_str(&r.id).unwrap();
+
+    let revoked = ApiKey::revoke(pool, id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !revoked {
+        return Ok(set_response(
+            response,
+            StatusCode::NOT_FOUND,
+            Some(r#"{"message": "API key not found"}"#),
+        ));
+    }
+
+    audit(&mut response, Event::ApiKeyRevoked, Some(claims.id), None);
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use dblib::users::{
+        api_keys::ApiKey,
+        auth_events::{AuthEvent, Event},
+    };
+    use hyper::{Body, Response, StatusCode};
+    use towerlib::api_keys::{create_api_key, verify_api_key};
+    use towerlib::auth::{now, AuthError};
+
+    use super::{delete_api_keys, get_auth_events, get_users, post_api_keys};
+    use crate::bob;
+    #[sqlx::test(fixtures("users"))]
+    async fn test_get_auth_events(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        AuthEvent::record(&pool, Event::Login, Some(1), None, None, None).await?;
+
+        let response = Response::new(Body::empty());
+        let res = get_auth_events(
+            &pool,
+            Some("userId=eq-1&createdAt=gt-2020-01-01T00:00:00Z"),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let events: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(events[0]["event"], "login");
+
+        let response = Response::new(Body::empty());
+        let res = get_auth_events(&pool, Some("createdAt=gt-2999-01-01T00:00:00Z"), response)
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(bytes, "[]");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_api_keys(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut body = Body::from(r#"{"name": "warehouse", "scopes": ["orders:read"]}"#);
+        let response = Response::new(Body::empty());
+        let res = post_api_keys(&pool, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(res["createdBy"], 1);
+        let key = res["key"].as_str().unwrap();
+        assert!(key.starts_with("tpk_"));
+
+        let verified = verify_api_key(&pool, key).await.unwrap();
+        assert!(verified.has_scope("orders:read"));
+        assert!(!verified.has_scope("orders:write"));
+        let used = ApiKey::list(&pool).await?[0].last_used_at.unwrap();
+
+        // Using it again straight away isn't written:
+        verify_api_key(&pool, key).await.unwrap();
+        assert_eq!(ApiKey::list(&pool).await?[0].last_used_at, Some(used));
+
+        sqlx::query("UPDATE api_keys SET last_used_at = NOW() - INTERVAL '1 hour'")
+            .execute(&pool)
+            .await?;
+        verify_api_key(&pool, key).await.unwrap();
+        assert!(ApiKey::list(&pool).await?[0].last_used_at.unwrap() > used);
+
+        assert!(matches!(
+            verify_api_key(&pool, "tpk_guessed").await,
+            Err(AuthError::Verify)
+        ));
+
+        let mut body = Body::from(format!(r#"{{"id": "{}"}}"#, verified.id));
+        let response = Response::new(Body::empty());
+        let res = delete_api_keys(&pool, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = Body::from(format!(r#"{{"id": "{}"}}"#, verified.id));
+        let response = Response::new(Body::empty());
+        let res = delete_api_keys(&pool, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        assert!(matches!(
+            verify_api_key(&pool, key).await,
+            Err(AuthError::Verify)
+        ));
+
+        let (key, _) = create_api_key(&pool, "old", &[], 1, Some(now() - 1)).await?;
+        assert!(matches!(
+            verify_api_key(&pool, &key).await,
+            Err(AuthError::Expired)
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_get_users(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (first_name, last_name, email, password, created_at) VALUES
+            ('alice', 'smith', 'alice@smith.com', '', '2020-01-01T00:00:00Z'),
+            ('carol', 'jones', 'carol@jones.com', '', '2021-01-01T00:00:00Z')
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        let get = |query: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let response = Response::new(Body::empty());
+                let res = get_users(&pool, Some(query), response).await.unwrap();
+                let status = res.status();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                let emails: Vec<String> = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .ok()
+                    .and_then(|v| v.as_array().cloned())
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|u| u["email"].as_str().unwrap().to_owned())
+                    .collect();
+                (status, emails)
+            }
+        };
+
+        let (status, emails) = get("email=eq-alice@smith.com").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(emails, ["alice@smith.com"]);
+
+        let (_, emails) = get("lastName=eq-smith&sort=firstName-asc").await;
+        assert_eq!(emails, ["alice@smith.com", "bob@smith.com"]);
+
+        let (_, emails) = get("createdAt=lt-2022-01-01T00:00:00Z&sort=createdAt-desc").await;
+        assert_eq!(emails, ["carol@jones.com", "alice@smith.com"]);
+
+        let (_, emails) = get("sort=id-desc&limit=1&offset=1").await;
+        assert_eq!(emails, ["alice@smith.com"]);
+
+        let (status, _) = get("createdAt=lt-yesterday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get("limit=many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+}
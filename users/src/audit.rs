@@ -0,0 +1,63 @@
+use dblib::users::auth_events::{AuthEvent, Event};
+use hyper::{header::USER_AGENT, Body, HeaderMap, Response};
+use sqlx::PgPool;
+use std::net::IpAddr;
+
+/// The size of the `user_agent` column, longer ones are cut short.
+const MAX_USER_AGENT: usize = 255;
+
+struct Entry {
+    event: Event,
+    user_id: Option<i64>,
+    email: Option<String>,
+}
+
+/// Events for `handle` to record once the handler returns, kept in the
+/// response's extensions so handlers don't need the request's IP or headers.
+#[derive(Default)]
+pub struct Audit(Vec<Entry>);
+
+/// Adds an event to the response, for `record`.
+pub fn audit(
+    response: &mut Response<Body>,
+    event: Event,
+    user_id: Option<i64>,
+    email: Option<&str>,
+) {
+    let extensions = response.extensions_mut();
+    if extensions.get::<Audit>().is_none() {
+        extensions.insert(Audit::default());
+    }
+
+    extensions.get_mut::<Audit>().unwrap().0.push(Entry {
+        event,
+        user_id,
+        email: email.map(|e| e.to_lowercase()),
+    });
+}
+
+/// Writes the events with the client's IP and user agent. A failure is
+/// logged rather than failing the request, which has already happened.
+pub async fn record(pool: &PgPool, audit: Audit, headers: &HeaderMap, ip: Option<IpAddr>) {
+    let ip = ip.map(|ip| ip.to_string());
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_USER_AGENT).collect::<String>());
+
+    for entry in audit.0 {
+        let res = AuthEvent::record(
+            pool,
+            entry.event,
+            entry.user_id,
+            entry.email.as_deref(),
+            ip.as_deref(),
+            user_agent.as_deref(),
+        )
+        .await;
+
+        if let Err(e) = res {
+            log::error!("Couldn't record {}: {}", entry.event.as_str(), e);
+        }
+    }
+}
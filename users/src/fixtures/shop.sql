@@ -0,0 +1,118 @@
+-- The shop database's tables, for exports and erasure:
+CREATE TABLE IF NOT EXISTS categories(
+    id        BIGSERIAL,
+    parent_id BIGINT REFERENCES "categories" (id),
+    name      VARCHAR(100) NOT NULL,
+    slug      VARCHAR(100) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX categories_parent_id ON categories (parent_id);
+
+CREATE TABLE IF NOT EXISTS inventory(
+    id          BIGSERIAL,
+    name        VARCHAR(100),
+    image_url   VARCHAR(255),
+    description TEXT,
+    category_id BIGINT REFERENCES "categories" (id),
+    created_at  TIMESTAMPTZ DEFAULT NOW(),
+    deleted_at  TIMESTAMPTZ,
+    search      TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', COALESCE(name, '')), 'A') ||
+        setweight(to_tsvector('english', COALESCE(description, '')), 'B')
+    ) STORED,
+    PRIMARY KEY (id)
+);
+CREATE INDEX inventory_search ON inventory USING GIN (search);
+CREATE INDEX inventory_category_id ON inventory (category_id);
+
+CREATE TABLE IF NOT EXISTS variants(
+    id           BIGSERIAL,
+    inventory_id BIGINT NOT NULL REFERENCES "inventory" (id),
+    sku          VARCHAR(64) UNIQUE NOT NULL,
+    price        INT NOT NULL,
+    quantity     INT NOT NULL,
+    size         VARCHAR(50),
+    weight_grams INT,
+    deleted_at   TIMESTAMPTZ,
+    CONSTRAINT   variants_quantity CHECK (quantity >= 0),
+    PRIMARY KEY (id)
+);
+CREATE INDEX variants_inventory_id ON variants (inventory_id);
+
+CREATE TABLE IF NOT EXISTS tags(
+    id   BIGSERIAL,
+    name VARCHAR(50) UNIQUE NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS inventory_tags(
+    inventory_id BIGINT REFERENCES "inventory" (id) ON DELETE CASCADE,
+    tag_id       BIGINT REFERENCES "tags" (id) ON DELETE CASCADE,
+    PRIMARY KEY (inventory_id, tag_id)
+);
+CREATE INDEX inventory_tags_tag_id ON inventory_tags (tag_id);
+
+CREATE TABLE IF NOT EXISTS address(
+    id         BIGSERIAL,
+    user_id    BIGINT,
+    first_name VARCHAR(100),
+    last_name  VARCHAR(100),
+    address_1  VARCHAR(100),
+    address_2  VARCHAR(100),
+    postcode   VARCHAR(100),
+    city       VARCHAR(100),
+    PRIMARY KEY (id)
+);
+CREATE INDEX address_user_id ON address (user_id);
+
+CREATE TABLE IF NOT EXISTS orders(
+    id UUID,
+    user_id BIGINT,
+    status VARCHAR(20) DEFAULT 'PENDING',
+    address_id BIGINT REFERENCES "address" (id),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+CREATE INDEX orders_user_id ON orders (user_id);
+
+-- unit_price is the variant's price when the order was made, so totals don't
+-- change with it:
+CREATE TABLE IF NOT EXISTS order_items(
+    order_id UUID REFERENCES "orders" (id),
+    variant_id BIGINT REFERENCES "variants" (id),
+    quantity INT,
+    unit_price INT NOT NULL
+);
+
+INSERT INTO categories (parent_id, name, slug) VALUES
+(NULL, 'Tea', 'tea'),
+(1, 'Black tea', 'black-tea'),
+(2, 'Earl Grey', 'earl-grey'),
+(2, 'English Breakfast', 'english-breakfast');
+
+INSERT INTO inventory (name, image_url, description, category_id) VALUES
+('Clipper Earl Grey', '', '', 3),
+('Twinings English Breakfast', '', '', 4);
+
+INSERT INTO variants (inventory_id, sku, price, quantity, size, weight_grams) VALUES
+(1, 'CLIPPER-EG-80', 299, 100, '80 bags', 200),
+(2, 'TWININGS-EB-160', 600, 100, '160 bags', 500),
+(1, 'CLIPPER-EG-40', 175, 100, '40 bags', 100);
+
+INSERT INTO tags (name) VALUES
+('organic'),
+('plastic-free');
+
+INSERT INTO inventory_tags (inventory_id, tag_id) VALUES
+(1, 1),
+(1, 2);
+
+INSERT INTO address (user_id, first_name, last_name, address_1, address_2, postcode, city) VALUES
+(1, 'bob', 'smith', '1 bob st', '', 'm1abc', 'manchester');
+
+INSERT INTO orders (id, user_id, status, address_id) VALUES
+('6f1c7e4a-1d2b-4c3a-9e8f-0a1b2c3d4e5f', 1, 'PAID', 1);
+
+INSERT INTO order_items VALUES
+('6f1c7e4a-1d2b-4c3a-9e8f-0a1b2c3d4e5f', 1, 1, 299),
+('6f1c7e4a-1d2b-4c3a-9e8f-0a1b2c3d4e5f', 2, 2, 600);
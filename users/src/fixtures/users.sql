@@ -4,8 +4,117 @@ CREATE TABLE IF NOT EXISTS users (
     last_name  VARCHAR(100),
     email      VARCHAR(100) UNIQUE,
     password   BYTEA,
+    email_verified_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
     PRIMARY KEY (id)
 );
 
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id         UUID,
+    family_id  UUID NOT NULL,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    token_hash BYTEA UNIQUE NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at    TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+CREATE INDEX refresh_tokens_family_id ON refresh_tokens (family_id);
+
+CREATE TABLE IF NOT EXISTS roles (
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    role       VARCHAR(20),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (user_id, role)
+);
+
+CREATE TABLE IF NOT EXISTS password_resets (
+    token_hash BYTEA,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at    TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (token_hash)
+);
+CREATE INDEX password_resets_user_id ON password_resets (user_id);
+
+CREATE TABLE IF NOT EXISTS totp (
+    user_id      BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    secret       BYTEA NOT NULL,
+    last_step    BIGINT,
+    confirmed_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    code_hash  BYTEA,
+    user_id    BIGINT REFERENCES "users" (id) ON DELETE CASCADE,
+    used_at    TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (code_hash)
+);
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
+
+-- Keys for scripts and integrations, only their hash is stored:
+CREATE TABLE IF NOT EXISTS api_keys (
+    id           UUID,
+    name         VARCHAR(100) NOT NULL,
+    scopes       VARCHAR(20)[] NOT NULL,
+    key_hash     BYTEA UNIQUE NOT NULL,
+    created_by   BIGINT REFERENCES "users" (id) ON DELETE SET NULL,
+    expires_at   TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+
+-- Append only, events are kept after the user is deleted, without their
+-- personal data, see erase_auth_events:
+CREATE TABLE IF NOT EXISTS auth_events (
+    id         BIGSERIAL,
+    user_id    BIGINT,
+    event      VARCHAR(20) NOT NULL,
+    email      VARCHAR(100),
+    ip         VARCHAR(45),
+    user_agent VARCHAR(255),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS auth_events_user_id ON auth_events (user_id);
+CREATE INDEX IF NOT EXISTS auth_events_created_at ON auth_events (created_at);
+
+-- Clears the personal data in a user's events, including failed logins with
+-- their email. The app should only need EXECUTE on this, not UPDATE on the
+-- table:
+CREATE OR REPLACE FUNCTION erase_auth_events(target_id BIGINT, target_email VARCHAR)
+RETURNS VOID
+LANGUAGE sql
+SECURITY DEFINER
+SET search_path = public
+AS $$
+    UPDATE auth_events SET email = NULL, ip = NULL, user_agent = NULL
+    WHERE user_id = target_id OR LOWER(email) = LOWER(target_email);
+$$;
+
+-- The only update let through is erase_auth_events', which runs as the
+-- function's owner and only clears an event's email, IP and user agent.
+-- Everything else about the event is kept:
+CREATE OR REPLACE RULE auth_events_no_update AS ON UPDATE TO auth_events
+    WHERE NOT (
+        current_user = (
+            SELECT pg_get_userbyid(proowner) FROM pg_proc
+            WHERE oid = 'erase_auth_events(BIGINT, VARCHAR)'::regprocedure
+        )
+        AND NEW.email IS NULL AND NEW.ip IS NULL AND NEW.user_agent IS NULL
+        AND NEW.id = OLD.id
+        AND NEW.user_id IS NOT DISTINCT FROM OLD.user_id
+        AND NEW.event = OLD.event
+        AND NEW.created_at IS NOT DISTINCT FROM OLD.created_at
+    )
+    DO INSTEAD NOTHING;
+CREATE OR REPLACE RULE auth_events_no_delete AS ON DELETE TO auth_events DO INSTEAD NOTHING;
+
 INSERT INTO users (first_name, last_name, email, password) VALUES 
 ('bob', 'smith', 'bob@smith.com', E'\\x673832414244616e4d6a6e646d636758504f50695a536b45506e334371444944544637396b7a466e366555');
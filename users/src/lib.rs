@@ -1,15 +1,61 @@
+pub mod admin;
+pub mod audit;
+pub mod mailer;
+pub mod me;
+pub mod password;
+pub mod throttle;
 pub mod token;
+pub mod totp;
+pub mod two_factor;
 
-use apilib::{set_response, App};
-use dblib::users::users::{Password, User};
-use hyper::{http::HeaderValue, Body, Method, Request, Response, StatusCode};
+use admin::{
+    delete_api_keys, delete_roles, get_api_keys, get_auth_events, get_users, post_api_keys,
+    post_roles, post_unlock,
+};
+use apilib::{
+    parse_query, set_response, set_response_v2,
+    validate::{validate, Validate, Validator, MAX_PASSWORD_LENGTH},
+    App,
+};
+use audit::{audit, Audit};
+use dblib::users::{
+    auth_events::Event,
+    roles::Roles,
+    tokens::RefreshToken,
+    totp::Totp,
+    users::{Password, User},
+};
+use hyper::{
+    header::{CACHE_CONTROL, RETRY_AFTER},
+    http::{Extensions, HeaderValue},
+    Body, Method, Request, Response, StatusCode,
+};
+use mailer::{Email, Mailer};
+use me::{delete_me, get_me, get_me_export, patch_me};
+use password::{post_password_forgot, post_password_reset};
+use redis::Client as RedisClient;
 use serde::Deserialize;
-use sqlx::PgPool;
-use std::{convert::Infallible, sync::Arc};
-use token::gen_token;
-use towerlib::session::{gen_session, SESSION_ID};
+use sqlx::{types::chrono, PgPool};
+use std::{convert::Infallible, env, net::IpAddr, sync::Arc};
+use throttle::Throttle;
+use token::{
+    gen_challenge_token, gen_opaque_token, gen_token, gen_verify_email_token, hash_opaque_token,
+    verify_email_token, REFRESH_TOKEN_TTL,
+};
+use towerlib::{
+    auth::{check_access, get_claims, revoke_token, revoke_user, Permission, Role},
+    keys::signing_keys,
+    session::{gen_session, SESSION_ID},
+};
+use two_factor::{post_2fa_confirm, post_2fa_enroll, post_token_2fa};
+use uuid::Uuid;
 
-pub async fn handle(app: Arc<App>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+pub async fn handle(
+    app: Arc<App>,
+    mailer: Arc<dyn Mailer>,
+    shop: PgPool,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
     let mut response = Response::new(Body::empty());
     response
         .headers_mut()
@@ -17,24 +63,204 @@ pub async fn handle(app: Arc<App>, req: Request<Body>) -> Result<Response<Body>,
 
     let (parts, mut body) = req.into_parts();
 
+    let permission = permission(&parts.method, parts.uri.path());
+    let scope = scope(&parts.method, parts.uri.path());
+    if let Err((code, message)) = check_access(permission, scope, &parts.extensions) {
+        let message = message.map(|m| m.to_string());
+        return Ok(set_response(response, code, message.as_deref()));
+    }
+
     let response = match (parts.method, parts.uri.path()) {
-        (Method::POST, "/") => post_sign_up(&app.pool, &mut body, response).await,
-        (Method::POST, "/token") => post_token(&app.pool, &mut body, response).await,
-        (Method::GET, "/session") => get_session(response).await,
+        (Method::POST, "/") => post_sign_up(&app.pool, mailer.as_ref(), &mut body, response).await,
+        (Method::POST, "/token") => {
+            post_token(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                parts.extensions.get::<IpAddr>().copied(),
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::POST, "/token/2fa") => {
+            post_token_2fa(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                parts.extensions.get::<IpAddr>().copied(),
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::POST, "/token/refresh") => {
+            post_token_refresh(&app.pool, &mut body, response).await
+        }
+        (Method::POST, "/logout") => {
+            post_logout(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                &parts.extensions,
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::POST, "/logout-all") => {
+            post_logout_all(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                &parts.extensions,
+                response,
+            )
+            .await
+        }
+        (Method::POST, "/roles") => {
+            post_roles(&app.pool, app.redis.as_ref().unwrap(), &mut body, response).await
+        }
+        (Method::DELETE, "/roles") => {
+            delete_roles(&app.pool, app.redis.as_ref().unwrap(), &mut body, response).await
+        }
+        (Method::POST, "/unlock") => {
+            post_unlock(app.redis.as_ref().unwrap(), &mut body, response).await
+        }
+        (Method::POST, "/password/forgot") => {
+            post_password_forgot(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                parts.extensions.get::<IpAddr>().copied(),
+                mailer.clone(),
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::POST, "/password/reset") => {
+            post_password_reset(&app.pool, app.redis.as_ref().unwrap(), &mut body, response).await
+        }
+        (Method::GET, "/verify-email") => {
+            get_verify_email(&app.pool, parts.uri.query(), response).await
+        }
+        (Method::POST, "/verify-email/resend") => {
+            post_verify_email_resend(&app.pool, mailer.as_ref(), &parts.extensions, response).await
+        }
+        (Method::POST, "/2fa/enroll") => {
+            post_2fa_enroll(&app.pool, &parts.extensions, response).await
+        }
+        (Method::POST, "/2fa/confirm") => {
+            post_2fa_confirm(&app.pool, &parts.extensions, &mut body, response).await
+        }
+        (Method::GET, "/users") => get_users(&app.pool, parts.uri.query(), response).await,
+        (Method::GET, "/auth-events") => {
+            get_auth_events(&app.pool, parts.uri.query(), response).await
+        }
+        (Method::GET, "/api-keys") => get_api_keys(&app.pool, response).await,
+        (Method::POST, "/api-keys") => {
+            post_api_keys(&app.pool, &parts.extensions, &mut body, response).await
+        }
+        (Method::DELETE, "/api-keys") => {
+            delete_api_keys(&app.pool, &parts.extensions, &mut body, response).await
+        }
+        (Method::GET, "/me") => get_me(&app.pool, &parts.extensions, response).await,
+        (Method::GET, "/me/export") => {
+            get_me_export(
+                &app.pool,
+                &shop,
+                app.redis.as_ref().unwrap(),
+                &parts.extensions,
+                response,
+            )
+            .await
+        }
+        (Method::PATCH, "/me") => {
+            patch_me(
+                &app.pool,
+                app.redis.as_ref().unwrap(),
+                mailer.as_ref(),
+                &parts.extensions,
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::DELETE, "/me") => {
+            delete_me(
+                &app.pool,
+                &shop,
+                app.redis.as_ref().unwrap(),
+                &parts.extensions,
+                &mut body,
+                response,
+            )
+            .await
+        }
+        (Method::GET, "/.well-known/jwks.json") => get_jwks(response).await,
+        (Method::GET, "/session") => get_session(app.redis.as_ref().unwrap(), response).await,
         _ => {
             *response.status_mut() = StatusCode::NOT_FOUND;
             Ok(response)
         }
     };
 
-    let response = match response {
+    let mut response = match response {
         Ok(r) => r,
         Err(code) => set_response(Response::new(Body::empty()), code, None),
     };
 
+    if let Some(events) = response.extensions_mut().remove::<Audit>() {
+        let ip = parts.extensions.get::<IpAddr>().copied();
+        audit::record(&app.pool, events, &parts.headers, ip).await;
+    }
+
     Ok(response)
 }
 
+/// Who can call each route, checked before the request is handled.
+fn permission(method: &Method, path: &str) -> Permission {
+    match (method, path) {
+        (&Method::POST, "/logout") | (&Method::POST, "/logout-all") => Permission::User,
+        (&Method::POST, "/verify-email/resend") => Permission::User,
+        (_, "/me") | (&Method::GET, "/me/export") => Permission::User,
+        (&Method::POST, "/2fa/enroll") | (&Method::POST, "/2fa/confirm") => Permission::User,
+        (&Method::POST, "/roles") | (&Method::DELETE, "/roles") => Permission::Admin,
+        (&Method::POST, "/unlock") => Permission::Admin,
+        (&Method::GET, "/users") | (&Method::GET, "/auth-events") => Permission::Admin,
+        (_, "/api-keys") => Permission::Admin,
+        _ => Permission::Public,
+    }
+}
+
+/// Which API key scope can call each route, keys can't call routes without
+/// one.
+fn scope(method: &Method, path: &str) -> Option<&'static str> {
+    match (method, path) {
+        (&Method::GET, "/users") => Some("users:read"),
+        _ => None,
+    }
+}
+
+/// bob from the `users` fixture, for tests of routes that need a user.
+#[cfg(test)]
+pub(crate) fn bob() -> Extensions {
+    let mut extensions = Extensions::new();
+    extensions.insert(towerlib::auth::Claims::new(
+        1,
+        "bob@smith.com".into(),
+        Role::User,
+        true,
+    ));
+    extensions
+}
+
+/// The sizes of the `users` columns.
+const MAX_NAME_LENGTH: usize = 100;
+
+const MAX_EMAIL_LENGTH: usize = 100;
+
+/// Longer than any token or code we issue.
+const MAX_TOKEN_LENGTH: usize = 4096;
+
+const MAX_CODE_LENGTH: usize = 32;
+
 #[derive(Deserialize)]
 struct SignupRequest {
     #[serde(rename(deserialize = "firstName", serialize = "firstName"))]
@@ -45,8 +271,28 @@ struct SignupRequest {
     password: String,
 }
 
+impl Validate for SignupRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("firstName", &self.first_name).max_length(
+            "firstName",
+            &self.first_name,
+            MAX_NAME_LENGTH,
+        );
+        v.required("lastName", &self.last_name).max_length(
+            "lastName",
+            &self.last_name,
+            MAX_NAME_LENGTH,
+        );
+        v.required("email", &self.email)
+            .max_length("email", &self.email, MAX_EMAIL_LENGTH)
+            .email("email", &self.email);
+        v.password("password", &self.password);
+    }
+}
+
 async fn post_sign_up(
     pool: &PgPool,
+    mailer: &dyn Mailer,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, StatusCode> {
@@ -60,7 +306,11 @@ async fn post_sign_up(
         StatusCode::UNPROCESSABLE_ENTITY
     })?;
 
-    let hash = Password::hash(&r.password).map_err(|e| {
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let hash = Password::hash_async(r.password).await.map_err(|e| {
         log::debug!("{}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
@@ -89,6 +339,15 @@ async fn post_sign_up(
         },
     };
 
+    send_verify_email(mailer, &user).await?;
+
+    audit(
+        &mut response,
+        Event::SignUp,
+        Some(user.id),
+        Some(&user.email),
+    );
+
     let res = serde_json::to_string(&user).map_err(|e| {
         log::debug!("{}", e);
         StatusCode::INTERNAL_SERVER_ERROR
@@ -106,8 +365,26 @@ struct TokenRequest {
     password: String,
 }
 
+impl Validate for TokenRequest {
+    // Only new passwords have to be long enough:
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email)
+            .max_length("email", &self.email, MAX_EMAIL_LENGTH);
+        v.required("password", &self.password).max_length(
+            "password",
+            &self.password,
+            MAX_PASSWORD_LENGTH,
+        );
+    }
+}
+
+/// `ip` is the client's address, failed logins are throttled per email and
+/// per IP. Users with 2FA on get a challenge instead of tokens, which
+/// `post_token_2fa` takes with their code.
 async fn post_token(
     pool: &PgPool,
+    redis: &RedisClient,
+    ip: Option<IpAddr>,
     body: &mut Body,
     mut response: Response<Body>,
 ) -> Result<Response<Body>, StatusCode> {
@@ -121,22 +398,28 @@ async fn post_token(
         StatusCode::UNPROCESSABLE_ENTITY
     })?;
 
-    let hash = Password::hash(&r.password).map_err(|e| {
-        log::debug!("{}", e);
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let throttle = Throttle::from_env();
+
+    let wait = throttle.check(redis, &r.email, ip).await.map_err(|e| {
+        log::error!("{}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    if let Some(wait) = wait {
+        return Ok(too_many_requests(response, wait));
+    }
 
-    let hash_bytes = hash.into_bytes();
-    let user = match User::from_email_and_password(pool, r.email, &hash_bytes).await {
+    let (user, stored) = match User::from_email_with_password(pool, &r.email).await {
         Ok(u) => u,
         Err(e) => match e {
             sqlx::Error::RowNotFound => {
-                response = set_response(
-                    response,
-                    StatusCode::UNAUTHORIZED,
-                    Some(r#"{"message": "Email or password incorrect"}"#),
-                );
-                return Ok(response);
+                // Hash and count these too, so neither the response nor its
+                // timing shows which emails have accounts:
+                Password::verify_dummy_async(r.password.clone()).await;
+                return login_failed(&throttle, redis, &r.email, ip, LOGIN_FAILED, response).await;
             }
             _ => {
                 // Other db error
@@ -146,20 +429,479 @@ async fn post_token(
         },
     };
 
-    let token = gen_token(&user).map_err(|e| {
-        log::debug!("{:?}", e);
+    if let Err(e) = Password::verify_async(r.password.clone(), stored.clone()).await {
+        log::debug!("{}", e);
+        return login_failed(&throttle, redis, &r.email, ip, LOGIN_FAILED, response).await;
+    }
+
+    if Password::needs_rehash(&stored) {
+        // Upgrade rows from the shared salt scheme, login shouldn't fail if
+        // this does:
+        match Password::hash_async(r.password.clone()).await {
+            Ok(hash) => {
+                if let Err(e) = user.set_password(pool, hash).await {
+                    log::error!("{}", e);
+                }
+            }
+            Err(e) => log::error!("{}", e),
+        }
+    }
+
+    let totp = Totp::get(pool, user.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if matches!(totp, Some(t) if t.confirmed) {
+        // Failures are only cleared once the login is complete, so guessing
+        // codes counts towards the lockout too:
+        let challenge = gen_challenge_token(&user).map_err(|e| {
+            log::error!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        let res = serde_json::json!({ "twoFactorRequired": true, "challenge": challenge });
+        *response.status_mut() = StatusCode::OK;
+        *response.body_mut() = Body::from(res.to_string());
+
+        return Ok(response);
+    }
+
+    throttle.success(redis, &r.email).await.map_err(|e| {
+        log::error!("{}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    response = set_tokens(pool, &user, None, response).await?;
+    audit(
+        &mut response,
+        Event::Login,
+        Some(user.id),
+        Some(&user.email),
+    );
+
     let res = serde_json::to_string(&user).unwrap();
     *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+const LOGIN_FAILED: &str = r#"{"message": "Email or password incorrect"}"#;
+
+async fn login_failed(
+    throttle: &Throttle,
+    redis: &RedisClient,
+    email: &str,
+    ip: Option<IpAddr>,
+    message: &str,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    audit(&mut response, Event::LoginFailed, None, Some(email));
+
+    let lockout = throttle.failure(redis, email, ip).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    match lockout {
+        Some(lockout) => Ok(too_many_requests(response, lockout)),
+        None => Ok(set_response(
+            response,
+            StatusCode::UNAUTHORIZED,
+            Some(message),
+        )),
+    }
+}
+
+fn too_many_requests(response: Response<Body>, retry_after: u64) -> Response<Body> {
+    let mut response = set_response(
+        response,
+        StatusCode::TOO_MANY_REQUESTS,
+        Some(r#"{"message": "Too many failed logins, try again later"}"#),
+    );
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+
+    response
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    #[serde(rename(deserialize = "refreshToken"))]
+    refresh_token: String,
+}
+
+impl Validate for RefreshRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("refreshToken", &self.refresh_token).max_length(
+            "refreshToken",
+            &self.refresh_token,
+            MAX_TOKEN_LENGTH,
+        );
+    }
+}
+
+async fn post_token_refresh(
+    pool: &PgPool,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: RefreshRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let invalid = |response| {
+        set_response(
+            response,
+            StatusCode::UNAUTHORIZED,
+            Some(r#"{"message": "Invalid refresh token"}"#),
+        )
+    };
+
+    let hash = hash_opaque_token(&r.refresh_token);
+    let token = match RefreshToken::from_hash(pool, &hash).await {
+        Ok(t) => t,
+        Err(e) => match e {
+            sqlx::Error::RowNotFound => return Ok(invalid(response)),
+            _ => {
+                log::debug!("{}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        },
+    };
+
+    if token.revoked_at.is_some() || token.expires_at <= chrono::Utc::now() {
+        return Ok(invalid(response));
+    }
+
+    let first_use = token.used_at.is_none()
+        && token.mark_used(pool).await.map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if !first_use {
+        // Token has already been rotated, so it has leaked. Revoke everything
+        // issued from the same login:
+        log::warn!(
+            "Refresh token reused for user {}, revoking family {}",
+            token.user_id,
+            token.family_id
+        );
+        token.revoke_family(pool).await.map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        return Ok(invalid(response));
+    }
+
+    let user = User::from_id(pool, token.user_id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    response = set_tokens(pool, &user, Some(token.family_id), response).await?;
+
+    let res = serde_json::to_string(&user).unwrap();
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+/// Adds a new access token and refresh token to the response headers.
+async fn set_tokens(
+    pool: &PgPool,
+    user: &User,
+    family_id: Option<Uuid>,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let roles = Roles::get(pool, user.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Admins without 2FA on, eg ones granted the role before it was needed,
+    // only get a user token:
+    let admin = roles.iter().any(|r| r == Role::Admin.as_str());
+    let role = match admin && two_factor_on(pool, user.id).await? {
+        true => Role::Admin,
+        false => Role::User,
+    };
+
+    let token = gen_token(user, role).map_err(|e| {
+        log::debug!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let (refresh_token, hash) = gen_opaque_token();
+    RefreshToken::new(pool, user.id, family_id, &hash, REFRESH_TOKEN_TTL)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
     response.headers_mut().insert("token", token);
+    response.headers_mut().insert(
+        "refresh-token",
+        HeaderValue::from_str(&refresh_token).unwrap(),
+    );
+
+    audit(&mut response, Event::TokenIssued, Some(user.id), None);
+
+    Ok(response)
+}
+
+/// Whether the user has confirmed a TOTP secret, which admin rights need.
+async fn two_factor_on(pool: &PgPool, user_id: i64) -> Result<bool, StatusCode> {
+    let totp = Totp::get(pool, user_id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(matches!(totp, Some(t) if t.confirmed))
+}
+
+/// Revokes the access token, and the refresh token's family if one is sent.
+async fn post_logout(
+    pool: &PgPool,
+    redis: &RedisClient,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !bytes.is_empty() {
+        let r: RefreshRequest = serde_json::from_slice(&bytes).map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+
+        if let Err(e) = validate(&r) {
+            return Ok(set_response_v2(response, e));
+        }
+
+        let hash = hash_opaque_token(&r.refresh_token);
+        match RefreshToken::from_hash(pool, &hash).await {
+            Ok(t) if t.user_id == claims.id => t.revoke_family(pool).await.map_err(|e| {
+                log::debug!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+            // Not this user's token, or it doesn't exist:
+            Ok(_) | Err(sqlx::Error::RowNotFound) => (),
+            Err(e) => {
+                log::debug!("{}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    revoke_token(redis, claims).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+/// Revokes every access and refresh token issued to the user.
+async fn post_logout_all(
+    pool: &PgPool,
+    redis: &RedisClient,
+    extensions: &Extensions,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    RefreshToken::revoke_user(pool, claims.id)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    revoke_user(redis, claims.id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+/// Sends a signed link to verify the user's current email address. Failing
+/// to send isn't an error, the user can ask for it again.
+async fn send_verify_email(mailer: &dyn Mailer, user: &User) -> Result<(), StatusCode> {
+    let token = gen_verify_email_token(user).map_err(|e| {
+        log::debug!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let url = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:8080".into());
+    let email = Email {
+        to: user.email.to_owned(),
+        subject: "Verify your email address".into(),
+        body: format!(
+            "Use the link below to verify your email address, it expires in a day:\n\n\
+            {}/verify-email?token={}",
+            url, token
+        ),
+    };
+
+    if let Err(e) = mailer.send(email).await {
+        log::error!("{}", e);
+    }
+
+    Ok(())
+}
+
+async fn get_verify_email(
+    pool: &PgPool,
+    query: Option<&str>,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let invalid = |response| {
+        set_response(
+            response,
+            StatusCode::BAD_REQUEST,
+            Some(r#"{"message": "Invalid or expired verification link"}"#),
+        )
+    };
+
+    let query = parse_query(query);
+    let token = match query.get("token") {
+        Some(t) => t,
+        None => return Ok(invalid(response)),
+    };
+
+    let (id, email) = match verify_email_token(token) {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("{:?}", e);
+            return Ok(invalid(response));
+        }
+    };
+
+    let user = match User::from_id(pool, id).await {
+        Ok(u) => u,
+        Err(sqlx::Error::RowNotFound) => return Ok(invalid(response)),
+        Err(e) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // The link was for an email the user has since changed:
+    let verified = user.set_email_verified(pool, &email).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !verified {
+        return Ok(invalid(response));
+    }
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "Email verified"}"#);
+
+    Ok(response)
+}
+
+async fn post_verify_email_resend(
+    pool: &PgPool,
+    mailer: &dyn Mailer,
+    extensions: &Extensions,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let user = User::from_id(pool, claims.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if user.email_verified_at.is_some() {
+        return Ok(set_response(
+            response,
+            StatusCode::CONFLICT,
+            Some(r#"{"message": "Email has already been verified"}"#),
+        ));
+    }
+
+    send_verify_email(mailer, &user).await?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+fn unauthorized(response: Response<Body>) -> Response<Body> {
+    set_response(
+        response,
+        StatusCode::UNAUTHORIZED,
+        Some(r#"{"message": "Unauthorized"}"#),
+    )
+}
+
+/// The public keys access tokens are signed with, for other services to
+/// verify them.
+async fn get_jwks(mut response: Response<Body>) -> Result<Response<Body>, StatusCode> {
+    let res = serde_json::to_string(&signing_keys().jwks()).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    response.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=600"),
+    );
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
 
     Ok(response)
 }
 
-async fn get_session(mut response: Response<Body>) -> Result<Response<Body>, StatusCode> {
-    let session_id = gen_session();
+async fn get_session(
+    redis: &RedisClient,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let session_id = gen_session(redis).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     response
         .headers_mut()
@@ -170,12 +912,33 @@ async fn get_session(mut response: Response<Body>) -> Result<Response<Body>, Sta
 
 #[cfg(test)]
 mod test {
-    use hyper::{Body, Response, StatusCode};
+    use hyper::{header::USER_AGENT, Body, Request, Response, StatusCode};
+    use sqlx::Row;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
 
-    use super::{post_sign_up, post_token, App};
+    use super::{
+        bob, get_verify_email, handle,
+        mailer::{FileMailer, LogMailer},
+        permission, post_logout, post_logout_all, post_sign_up, post_token, post_token_refresh,
+        scope,
+        token::use_test_keys,
+        App,
+    };
+    use dblib::users::users::{Password, User};
+    use hyper::http::Extensions;
+    use hyper::service::{service_fn, Service};
+    use hyper::Method;
+    use redis::Client as RedisClient;
+    use towerlib::auth::{is_revoked, Auth, Claims, Permission, Role};
+    use towerlib::keys::signing_keys;
 
     #[sqlx::test(fixtures("users"))]
     async fn test_sign_up(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
         let app = App::new(pool, None);
 
         let mut body = Body::from(
@@ -190,16 +953,113 @@ mod test {
 
         let response = Response::new(Body::empty());
 
-        let res = post_sign_up(&app.pool, &mut body, response).await.unwrap();
+        let res = post_sign_up(&app.pool, &LogMailer, &mut body, response)
+            .await
+            .unwrap();
 
         assert_eq!(res.status(), StatusCode::CREATED);
 
         Ok(())
     }
 
+    #[sqlx::test(fixtures("users"))]
+    async fn test_sign_up_invalid(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+
+        let mut body = Body::from(
+            "\
+{
+    \"firstName\": \" \",
+    \"lastName\": \"smith\",
+    \"email\": \"bob\",
+    \"password\": \"short\"
+}",
+        );
+
+        let response = Response::new(Body::empty());
+
+        let res = post_sign_up(&app.pool, &LogMailer, &mut body, response)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let fields: Vec<(&str, &str)> = res["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["field"].as_str().unwrap(), e["code"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("firstName", "required"),
+                ("email", "invalid_email"),
+                ("password", "too_short")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_verify_email(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+
+        let path = std::env::temp_dir().join(format!("mail-{}.jsonl", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(&path);
+
+        let mut body = Body::from(
+            "\
+{
+    \"firstName\": \"bob\",
+    \"lastName\": \"smith\",
+    \"email\": \"bob@mail.com\",
+    \"password\": \"password123\"
+}",
+        );
+
+        let response = Response::new(Body::empty());
+        post_sign_up(&app.pool, &mailer, &mut body, response)
+            .await
+            .unwrap();
+
+        let emails = mailer.read().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(emails.len(), 1);
+
+        let query = emails[0].body.split('?').nth(1).unwrap().trim();
+
+        let response = Response::new(Body::empty());
+        let res = get_verify_email(&app.pool, Some("token=invalid"), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let response = Response::new(Body::empty());
+        let res = get_verify_email(&app.pool, Some(query), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let user = User::from_email(&app.pool, "bob@mail.com").await?;
+        assert!(user.email_verified_at.is_some());
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("users"))]
     async fn test_token(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
         let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
 
         let mut body = Body::from(
             "\
@@ -211,11 +1071,355 @@ mod test {
 
         let response = Response::new(Body::empty());
 
-        let res = post_token(&app.pool, &mut body, response).await.unwrap();
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
 
         assert_eq!(res.status(), StatusCode::OK);
         assert!(res.headers().get("token").is_some());
 
         Ok(())
     }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_token_upgrades_legacy_hash(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let (_, stored) = User::from_email_with_password(&app.pool, "bob@smith.com").await?;
+        assert!(Password::needs_rehash(&stored));
+
+        let mut body = Body::from(
+            "\
+{
+    \"email\": \"bob@smith.com\",
+    \"password\": \"password\"
+}",
+        );
+
+        let response = Response::new(Body::empty());
+
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let (_, stored) = User::from_email_with_password(&app.pool, "bob@smith.com").await?;
+        assert!(!Password::needs_rehash(&stored));
+        assert!(Password::verify("password", &stored).is_ok());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_token_wrong_password(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        // Failures are counted in redis, so they can't be left against bob:
+        let email = format!("{}@smith.com", uuid::Uuid::new_v4());
+        sqlx::query(
+            r#"
+            INSERT INTO users (first_name, last_name, email, password)
+            SELECT first_name, last_name, $1, password FROM users WHERE id = 1
+            "#,
+        )
+        .bind(&email)
+        .execute(&app.pool)
+        .await?;
+
+        let mut body = Body::from(format!(
+            "{{\"email\": \"{}\", \"password\": \"wrong\"}}",
+            email
+        ));
+
+        let response = Response::new(Body::empty());
+
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_audit(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let app = App::new(pool, Some(redis));
+
+        let email = format!("{}@smith.com", uuid::Uuid::new_v4());
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/token")
+            .header(USER_AGENT, "curl/7.88.1")
+            .body(Body::from(format!(
+                "{{\"email\": \"{}\", \"password\": \"wrong\"}}",
+                email
+            )))
+            .unwrap();
+        // The IP's failures are kept in redis too:
+        let [a, b, c, d] = rand::random::<[u8; 4]>();
+        let ip = IpAddr::V4(Ipv4Addr::new(a, b, c, d));
+        req.extensions_mut().insert(ip);
+
+        let res = handle(app.clone(), Arc::new(LogMailer), app.pool.clone(), req)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let row = sqlx::query("SELECT * FROM auth_events WHERE email = $1")
+            .bind(&email)
+            .fetch_one(&app.pool)
+            .await?;
+        assert_eq!(row.try_get::<String, _>("event")?, "login_failed");
+        assert_eq!(row.try_get::<Option<i64>, _>("user_id")?, None);
+        assert_eq!(row.try_get::<String, _>("ip")?, ip.to_string());
+        assert_eq!(row.try_get::<String, _>("user_agent")?, "curl/7.88.1");
+
+        // Append only:
+        sqlx::query("DELETE FROM auth_events")
+            .execute(&app.pool)
+            .await?;
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM auth_events")
+            .fetch_one(&app.pool)
+            .await?;
+        assert_eq!(count, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_token_refresh(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let mut body = Body::from(
+            "\
+{
+    \"email\": \"bob@smith.com\",
+    \"password\": \"password\"
+}",
+        );
+
+        let response = Response::new(Body::empty());
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+        let first = res
+            .headers()
+            .get("refresh-token")
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        let refresh = |token: &str| Body::from(format!("{{\"refreshToken\": \"{}\"}}", token));
+
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(first), response)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("token").is_some());
+        let second = res
+            .headers()
+            .get("refresh-token")
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        // Reusing the first token revokes the whole family:
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(first), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(second), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_token_refresh_with_expired_token(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let refresh_token = login(&app, &redis).await;
+
+        let svc = {
+            let app = app.clone();
+            service_fn(move |req| handle(app.clone(), Arc::new(LogMailer), app.pool.clone(), req))
+        };
+        let mut svc = Auth::new(svc, redis, signing_keys().verifier());
+
+        // Clients can send their expired token along with the refresh:
+        let mut claims = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        claims.exp = claims.iat - 1;
+        let expired = signing_keys().sign(&claims).unwrap();
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/token/refresh")
+            .header("Authorization", format!("Bearer {}", expired))
+            .body(Body::from(format!(
+                "{{\"refreshToken\": \"{}\"}}",
+                refresh_token
+            )))
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("token").is_some());
+
+        // But it doesn't get them into routes that need a user:
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/me")
+            .header("Authorization", format!("Bearer {}", expired))
+            .body(Body::empty())
+            .unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    async fn login(app: &App, redis: &RedisClient) -> String {
+        let mut body = Body::from("{\"email\": \"bob@smith.com\", \"password\": \"password\"}");
+        let response = Response::new(Body::empty());
+        let res = post_token(&app.pool, redis, None, &mut body, response)
+            .await
+            .unwrap();
+
+        res.headers()
+            .get("refresh-token")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_logout(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let refresh = |token: &str| Body::from(format!("{{\"refreshToken\": \"{}\"}}", token));
+
+        let token = login(&app, &redis).await;
+        let other = login(&app, &redis).await;
+        let extensions = bob();
+
+        let response = Response::new(Body::empty());
+        let res = post_logout(
+            &app.pool,
+            &redis,
+            &extensions,
+            &mut refresh(&token),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let claims = extensions.get::<Claims>().unwrap();
+        assert!(is_revoked(&redis, claims).await.unwrap());
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(&token), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Other sessions are kept:
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(&other), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let response = Response::new(Body::empty());
+        let res = post_logout(
+            &app.pool,
+            &redis,
+            &Extensions::new(),
+            &mut Body::empty(),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_logout_all(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let refresh = |token: &str| Body::from(format!("{{\"refreshToken\": \"{}\"}}", token));
+
+        let token = login(&app, &redis).await;
+        let extensions = bob();
+
+        let response = Response::new(Body::empty());
+        let res = post_logout_all(&app.pool, &redis, &extensions, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let claims = extensions.get::<Claims>().unwrap();
+        assert!(is_revoked(&redis, claims).await.unwrap());
+        let response = Response::new(Body::empty());
+        let res = post_token_refresh(&app.pool, &mut refresh(&token), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Logging in again works:
+        let claims = Claims::new(1, "bob@smith.com".into(), Role::User, true);
+        assert!(!is_revoked(&redis, &claims).await.unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_permission() {
+        assert_eq!(permission(&Method::POST, "/token"), Permission::Public);
+        assert_eq!(permission(&Method::POST, "/logout"), Permission::User);
+        assert_eq!(permission(&Method::POST, "/roles"), Permission::Admin);
+        assert_eq!(permission(&Method::DELETE, "/roles"), Permission::Admin);
+        assert_eq!(permission(&Method::POST, "/unlock"), Permission::Admin);
+        assert_eq!(permission(&Method::GET, "/users"), Permission::Admin);
+        assert_eq!(permission(&Method::GET, "/auth-events"), Permission::Admin);
+        assert_eq!(permission(&Method::POST, "/api-keys"), Permission::Admin);
+        assert_eq!(permission(&Method::DELETE, "/api-keys"), Permission::Admin);
+        assert_eq!(permission(&Method::GET, "/me/export"), Permission::User);
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(scope(&Method::GET, "/users"), Some("users:read"));
+        // Keys can't manage keys:
+        assert_eq!(scope(&Method::POST, "/api-keys"), None);
+        assert_eq!(scope(&Method::GET, "/auth-events"), None);
+    }
 }
@@ -0,0 +1,255 @@
+use std::{env, fmt, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailerError {
+    Io(std::io::Error),
+    Smtp(String),
+    /// A line break in an address or subject, which would let it add headers
+    /// or SMTP commands.
+    InvalidHeader,
+}
+
+impl fmt::Display for MailerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailerError::Io(e) => write!(f, "mailer io error: {}", e),
+            MailerError::Smtp(reply) => write!(f, "unexpected smtp reply: {}", reply),
+            MailerError::InvalidHeader => write!(f, "line break in an email header"),
+        }
+    }
+}
+
+impl std::error::Error for MailerError {}
+
+impl From<std::io::Error> for MailerError {
+    fn from(e: std::io::Error) -> Self {
+        MailerError::Io(e)
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailerError>;
+}
+
+/// Picks a backend with the `MAILER` env var: `smtp`, `file` or `log` (the
+/// default).
+pub fn from_env() -> Arc<dyn Mailer> {
+    match env::var("MAILER").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::new(
+            env::var("SMTP_ADDR").unwrap_or_else(|_| "127.0.0.1:1025".into()),
+            env::var("MAIL_FROM").unwrap_or_else(|_| "teapot@localhost".into()),
+        )),
+        Ok("file") => Arc::new(FileMailer::new(
+            env::var("MAIL_FILE").expect("MAIL_FILE must be set"),
+        )),
+        _ => Arc::new(LogMailer),
+    }
+}
+
+/// Only logs emails, for local development.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        log::info!(
+            "Email to {}, subject: {}\n{}",
+            email.to,
+            email.subject,
+            email.body
+        );
+
+        Ok(())
+    }
+}
+
+/// Appends each email to a file as a line of JSON, so tests can read them.
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub async fn read(&self) -> Result<Vec<Email>, MailerError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+
+        contents
+            .lines()
+            .map(|l| serde_json::from_str(l).map_err(|e| MailerError::Io(e.into())))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let mut line = serde_json::to_string(&email).map_err(|e| MailerError::Io(e.into()))?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        // Tokio writes in the background, make sure it's done before
+        // returning:
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Plain SMTP without TLS or auth, for a local mail catcher like MailHog.
+pub struct SmtpMailer {
+    addr: String,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(addr: String, from: String) -> Self {
+        Self { addr, from }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        if [&self.from, &email.to, &email.subject]
+            .iter()
+            .any(|h| h.contains(['\r', '\n']))
+        {
+            return Err(MailerError::InvalidHeader);
+        }
+
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        expect_reply(&mut read, "220").await?;
+        command(&mut write, &mut read, "EHLO localhost", "250").await?;
+        command(
+            &mut write,
+            &mut read,
+            &format!("MAIL FROM:<{}>", self.from),
+            "250",
+        )
+        .await?;
+        command(
+            &mut write,
+            &mut read,
+            &format!("RCPT TO:<{}>", email.to),
+            "250",
+        )
+        .await?;
+        command(&mut write, &mut read, "DATA", "354").await?;
+
+        let mut message = format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\n\r\n",
+            self.from, email.to, email.subject
+        );
+        for line in email.body.lines() {
+            // Lines starting with a dot are escaped with another:
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push('.');
+
+        command(&mut write, &mut read, &message, "250").await?;
+        command(&mut write, &mut read, "QUIT", "221").await?;
+
+        Ok(())
+    }
+}
+
+async fn command<W, R>(
+    write: &mut W,
+    read: &mut R,
+    line: &str,
+    code: &str,
+) -> Result<(), MailerError>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    write.write_all(line.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+
+    expect_reply(read, code).await
+}
+
+async fn expect_reply<R>(read: &mut R, code: &str) -> Result<(), MailerError>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            return Err(MailerError::Smtp("connection closed".into()));
+        }
+
+        if !line.starts_with(code) {
+            return Err(MailerError::Smtp(line.trim_end().into()));
+        }
+
+        // Multiline replies continue with "250-":
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Email, Mailer, MailerError, SmtpMailer};
+
+    #[tokio::test]
+    async fn test_smtp_rejects_line_breaks() {
+        // Nothing listens here, it has to fail before connecting:
+        let mailer = SmtpMailer::new("127.0.0.1:1".into(), "teapot@localhost".into());
+
+        for (to, subject) in [
+            ("bob@smith.com>\r\nRCPT TO:<alice@smith.com", "Hello"),
+            ("bob@smith.com", "Hello\nBcc: alice@smith.com"),
+        ] {
+            let email = Email {
+                to: to.into(),
+                subject: subject.into(),
+                body: String::new(),
+            };
+            assert!(matches!(
+                mailer.send(email).await,
+                Err(MailerError::InvalidHeader)
+            ));
+        }
+
+        let email = Email {
+            to: "bob@smith.com".into(),
+            subject: "Hello".into(),
+            body: String::new(),
+        };
+        assert!(matches!(mailer.send(email).await, Err(MailerError::Io(_))));
+    }
+}
@@ -1,31 +1,50 @@
 use std::{convert::Infallible, net::SocketAddr};
 
 use apilib::App;
-use dblib::connect;
+use dblib::{connect, users::users::init_password_hashing};
 use hyper::{
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Method, Server,
+    Method, Request, Server,
 };
+use redis::Client as RedisClient;
 use tower_http::cors::{Any, Cors};
-use towerlib::logging::Logging;
+use towerlib::{auth::Auth, keys::signing_keys, logging::Logging};
+use users::mailer;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    init_password_hashing().unwrap();
 
     let pool = connect("users").await.unwrap();
+    // For data exports and erasure, which cover the user's shop data too:
+    let shop = connect("shop").await.unwrap();
+    let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
 
-    let app = App::new(pool, None);
+    let app = App::new(pool.clone(), Some(redis.clone()));
+    let mailer = mailer::from_env();
+    let verifier = signing_keys().verifier();
 
-    let make_service = make_service_fn(move |_: &AddrStream| {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
         // Clone for each invocation of make_service
         let app = app.clone();
-
-        let svc = service_fn(move |req| users::handle(app.clone(), req));
+        let pool = pool.clone();
+        let redis = redis.clone();
+        let mailer = mailer.clone();
+        let shop = shop.clone();
+        let verifier = verifier.clone();
+        let ip = conn.remote_addr().ip();
+
+        let svc = service_fn(move |mut req: Request<_>| {
+            // Handlers read the client's IP from the extensions:
+            req.extensions_mut().insert(ip);
+            users::handle(app.clone(), mailer.clone(), shop.clone(), req)
+        });
+        let svc = Auth::new(svc, redis, verifier).with_api_keys(pool);
         let svc = Logging::new(svc);
         let svc = Cors::new(svc)
-            .allow_methods([Method::GET, Method::POST])
+            .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
             .allow_origin(Any);
 
         async move { Ok::<_, Infallible>(svc) }
@@ -0,0 +1,593 @@
+use apilib::{
+    set_response, set_response_v2,
+    validate::{validate, Validate, Validator, MAX_PASSWORD_LENGTH},
+};
+use dblib::{
+    shop::personal_data::PersonalData,
+    users::{
+        auth_events::{AuthEvent, Event},
+        roles::Roles,
+        tokens::RefreshToken,
+        users::{Password, User},
+    },
+};
+use hyper::{
+    header::CONTENT_DISPOSITION,
+    http::{Extensions, HeaderValue},
+    Body, Response, StatusCode,
+};
+use redis::Client as RedisClient;
+use serde::Deserialize;
+use sqlx::{types::chrono, PgPool};
+use towerlib::{
+    auth::{get_claims, revoke_user},
+    cart::{delete_user_cart, get_user_cart},
+};
+
+use crate::{
+    audit::audit, mailer::Mailer, send_verify_email, set_tokens, unauthorized, MAX_EMAIL_LENGTH,
+    MAX_NAME_LENGTH,
+};
+
+pub async fn get_me(
+    pool: &PgPool,
+    extensions: &Extensions,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let user = User::from_id(pool, claims.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let res = serde_json::to_string(&user).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+/// Everything held about the user across the users and shop databases and
+/// their cart, as one download.
+pub async fn get_me_export(
+    pool: &PgPool,
+    shop: &PgPool,
+    redis: &RedisClient,
+    extensions: &Extensions,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let user = User::from_id(pool, claims.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let roles = Roles::get(pool, claims.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let auth_events = AuthEvent::from_user_id(pool, claims.id)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let shop_data = PersonalData::get(shop, claims.id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let cart = get_user_cart(redis, claims.id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let res = serde_json::json!({
+        "exportedAt": chrono::Utc::now().to_rfc3339(),
+        "user": user,
+        "roles": roles,
+        "authEvents": auth_events,
+        "addresses": shop_data.addresses,
+        "orders": shop_data.orders,
+        "orderItems": shop_data.order_items,
+        "cart": cart,
+    });
+
+    response.headers_mut().insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static(r#"attachment; filename="export.json""#),
+    );
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res.to_string());
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct PatchMeRequest {
+    #[serde(rename(deserialize = "firstName"))]
+    first_name: Option<String>,
+    #[serde(rename(deserialize = "lastName"))]
+    last_name: Option<String>,
+    email: Option<String>,
+    password: Option<String>,
+    #[serde(rename(deserialize = "currentPassword"))]
+    current_password: Option<String>,
+}
+
+impl Validate for PatchMeRequest {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(name) = &self.first_name {
+            v.required("firstName", name)
+                .max_length("firstName", name, MAX_NAME_LENGTH);
+        }
+        if let Some(name) = &self.last_name {
+            v.required("lastName", name)
+                .max_length("lastName", name, MAX_NAME_LENGTH);
+        }
+        if let Some(email) = &self.email {
+            v.required("email", email)
+                .max_length("email", email, MAX_EMAIL_LENGTH)
+                .email("email", email);
+        }
+        if let Some(password) = &self.password {
+            v.password("password", password);
+        }
+        if let Some(password) = &self.current_password {
+            v.max_length("currentPassword", password, MAX_PASSWORD_LENGTH);
+        }
+    }
+}
+
+/// Changing the email or password revokes the user's other tokens, new ones
+/// are sent back with the response.
+pub async fn patch_me(
+    pool: &PgPool,
+    redis: &RedisClient,
+    mailer: &dyn Mailer,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: PatchMeRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let (mut user, stored) = User::from_id_with_password(pool, claims.id)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let email = r.email.filter(|email| *email != user.email);
+    let email_changed = email.is_some();
+
+    // Both can be used to take over the account, so a token isn't enough:
+    if email_changed || r.password.is_some() {
+        let current = r.current_password.as_deref().unwrap_or("");
+        if let Err(e) = Password::verify_async(current.to_owned(), stored).await {
+            log::debug!("{}", e);
+            return Ok(set_response(
+                response,
+                StatusCode::FORBIDDEN,
+                Some(r#"{"message": "Current password is incorrect"}"#),
+            ));
+        }
+    }
+
+    let hash = match &r.password {
+        Some(password) => Some(Password::hash_async(password.clone()).await.map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?),
+        None => None,
+    };
+    let password_changed = hash.is_some();
+
+    match user
+        .update(pool, r.first_name, r.last_name, email, hash)
+        .await
+    {
+        Ok(_) => (),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+            // Unique violation:
+            return Ok(set_response(
+                response,
+                StatusCode::CONFLICT,
+                Some(r#"{"message": "Email is already in use"}"#),
+            ));
+        }
+        Err(e) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    if email_changed {
+        send_verify_email(mailer, &user).await?;
+        audit(
+            &mut response,
+            Event::EmailChange,
+            Some(user.id),
+            Some(&user.email),
+        );
+    }
+
+    if password_changed {
+        audit(&mut response, Event::PasswordChange, Some(user.id), None);
+    }
+
+    if email_changed || password_changed {
+        RefreshToken::revoke_user(pool, user.id)
+            .await
+            .map_err(|e| {
+                log::debug!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        revoke_user(redis, user.id).await.map_err(|e| {
+            log::error!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        response = set_tokens(pool, &user, None, response).await?;
+    }
+
+    let res = serde_json::to_string(&user).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct DeleteMeRequest {
+    password: String,
+}
+
+impl Validate for DeleteMeRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("password", &self.password).max_length(
+            "password",
+            &self.password,
+            MAX_PASSWORD_LENGTH,
+        );
+    }
+}
+
+/// Deleting the account needs the password, so a stolen token isn't enough.
+/// The user's addresses and carts go with it, and their orders are kept
+/// without anything linking them to the user. Auth events are kept for the
+/// log, but without their email, IP or user agent.
+pub async fn delete_me(
+    pool: &PgPool,
+    shop: &PgPool,
+    redis: &RedisClient,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: DeleteMeRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let (user, stored) = User::from_id_with_password(pool, claims.id)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if let Err(e) = Password::verify_async(r.password, stored).await {
+        log::debug!("{}", e);
+        return Ok(set_response(
+            response,
+            StatusCode::FORBIDDEN,
+            Some(r#"{"message": "Password is incorrect"}"#),
+        ));
+    }
+
+    let id = user.id;
+
+    // The shop data goes first, so the account is still there to retry with
+    // if it fails:
+    PersonalData::erase(shop, id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    delete_user_cart(redis, id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    AuthEvent::erase(pool, id, &user.email).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    user.delete(pool).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    revoke_user(redis, id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use apilib::App;
+    use dblib::{
+        shop::personal_data::PersonalData,
+        users::{
+            auth_events::{AuthEvent, Event},
+            users::{Password, User},
+        },
+    };
+    use hyper::{Body, Response, StatusCode};
+    use redis::{AsyncCommands, Client as RedisClient};
+    use sqlx::Row;
+    use towerlib::cart::{add_user_session, cart_key};
+
+    use super::{delete_me, get_me_export, patch_me};
+    use crate::{bob, mailer::LogMailer, token::use_test_keys};
+    #[sqlx::test(fixtures("users"))]
+    async fn test_patch_me(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        // Changing the password needs the current one:
+        let mut body =
+            Body::from("{\"password\": \"password123\", \"currentPassword\": \"wrong\"}");
+        let response = Response::new(Body::empty());
+        let res = patch_me(&app.pool, &redis, &LogMailer, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // So does changing the email:
+        let mut body = Body::from("{\"email\": \"robert@smith.com\"}");
+        let response = Response::new(Body::empty());
+        let res = patch_me(&app.pool, &redis, &LogMailer, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // A taken email leaves the name as it was:
+        sqlx::query("INSERT INTO users (first_name, last_name, email, password) VALUES ('alice', 'smith', 'alice@smith.com', '')")
+            .execute(&app.pool)
+            .await?;
+        let mut body = Body::from(
+            "{\"firstName\": \"robert\", \"email\": \"alice@smith.com\", \"currentPassword\": \"password\"}",
+        );
+        let response = Response::new(Body::empty());
+        let res = patch_me(&app.pool, &redis, &LogMailer, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let name: String = sqlx::query_scalar("SELECT first_name FROM users WHERE id = 1")
+            .fetch_one(&app.pool)
+            .await?;
+        assert_eq!(name, "bob");
+
+        let mut body = Body::from(
+            "\
+{
+    \"firstName\": \"robert\",
+    \"email\": \"robert@smith.com\",
+    \"password\": \"password123\",
+    \"currentPassword\": \"password\"
+}",
+        );
+        let response = Response::new(Body::empty());
+        let res = patch_me(&app.pool, &redis, &LogMailer, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("token").is_some());
+
+        let (user, stored) = User::from_email_with_password(&app.pool, "robert@smith.com").await?;
+        assert!(user.email_verified_at.is_none());
+        assert!(Password::verify("password123", &stored).is_ok());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users", "shop"))]
+    async fn test_get_me_export(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        AuthEvent::record(&app.pool, Event::Login, Some(1), None, None, None).await?;
+
+        let response = Response::new(Body::empty());
+        // The shop tables are loaded into the same database:
+        let res = get_me_export(&app.pool, &app.pool, &redis, &bob(), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let export: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(export["user"]["email"], "bob@smith.com");
+        assert_eq!(export["authEvents"][0]["event"], "login");
+        assert_eq!(export["addresses"][0]["postcode"], "m1abc");
+        assert_eq!(export["orders"][0]["total"], 299 + 2 * 600);
+        assert_eq!(export["orderItems"].as_array().unwrap().len(), 2);
+        assert_eq!(export["orderItems"][0]["sku"], "CLIPPER-EG-80");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users", "shop"))]
+    async fn test_delete_me(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let mut body = Body::from("{\"password\": \"wrong\"}");
+        let response = Response::new(Body::empty());
+        let res = delete_me(&app.pool, &app.pool, &redis, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let ip = Some("10.0.0.1");
+        AuthEvent::record(&app.pool, Event::Login, Some(1), None, ip, Some("curl")).await?;
+        let email = Some("BOB@smith.com");
+        AuthEvent::record(&app.pool, Event::LoginFailed, None, email, ip, None).await?;
+        let email = Some("alice@smith.com");
+        AuthEvent::record(&app.pool, Event::LoginFailed, None, email, ip, None).await?;
+
+        // A guest cart from a session bob has been logged in with:
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let mut con = redis.get_async_connection().await.unwrap();
+        add_user_session(&mut con, 1, &session_id).await.unwrap();
+        con.sadd::<_, _, ()>(cart_key(&session_id), "{}")
+            .await
+            .unwrap();
+
+        let mut body = Body::from("{\"password\": \"password\"}");
+        let response = Response::new(Body::empty());
+        let res = delete_me(&app.pool, &app.pool, &redis, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let exists: bool = con.exists(cart_key(&session_id)).await.unwrap();
+        assert!(!exists);
+
+        // The events are kept, without bob's personal data:
+        let rows = sqlx::query("SELECT * FROM auth_events ORDER BY id")
+            .fetch_all(&app.pool)
+            .await?;
+        assert_eq!(rows.len(), 3);
+        for row in &rows[..2] {
+            assert_eq!(row.try_get::<Option<String>, _>("email")?, None);
+            assert_eq!(row.try_get::<Option<String>, _>("ip")?, None);
+            assert_eq!(row.try_get::<Option<String>, _>("user_agent")?, None);
+        }
+        assert_eq!(rows[0].try_get::<String, _>("event")?, "login");
+        assert_eq!(
+            rows[2].try_get::<Option<String>, _>("email")?.as_deref(),
+            Some("alice@smith.com")
+        );
+
+        // Other updates still do nothing:
+        sqlx::query("UPDATE auth_events SET event = 'sign_up', email = NULL, ip = NULL")
+            .execute(&app.pool)
+            .await?;
+        let row = sqlx::query("SELECT * FROM auth_events WHERE id = $1")
+            .bind(rows[2].try_get::<i64, _>("id")?)
+            .fetch_one(&app.pool)
+            .await?;
+        assert_eq!(row.try_get::<String, _>("event")?, "login_failed");
+        assert!(row.try_get::<Option<String>, _>("email")?.is_some());
+
+        // Nor does clearing the personal data outside erase_auth_events:
+        let mut tx = app.pool.begin().await?;
+        sqlx::query(
+            "DO $$ BEGIN CREATE ROLE auth_events_app; \
+            EXCEPTION WHEN duplicate_object THEN NULL; END $$",
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("GRANT SELECT, UPDATE ON auth_events TO auth_events_app")
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("SET LOCAL ROLE auth_events_app")
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE auth_events SET email = NULL, ip = NULL, user_agent = NULL")
+            .execute(&mut tx)
+            .await?;
+        let row = sqlx::query("SELECT * FROM auth_events WHERE id = $1")
+            .bind(rows[2].try_get::<i64, _>("id")?)
+            .fetch_one(&mut tx)
+            .await?;
+        assert!(row.try_get::<Option<String>, _>("email")?.is_some());
+        tx.rollback().await?;
+
+        assert!(matches!(
+            User::from_id(&app.pool, 1).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        // The order is kept for accounting, but not who made it:
+        let data = PersonalData::get(&app.pool, 1).await?;
+        assert!(data.addresses.is_empty());
+        assert!(data.orders.is_empty());
+
+        let row = sqlx::query(
+            "SELECT user_id, address_id, SUM(order_items.quantity * unit_price) AS total FROM orders \
+            JOIN order_items ON orders.id = order_items.order_id \
+            JOIN variants ON order_items.variant_id = variants.id \
+            GROUP BY orders.id",
+        )
+        .fetch_one(&app.pool)
+        .await?;
+        assert_eq!(row.try_get::<Option<i64>, _>("user_id")?, None);
+        assert_eq!(row.try_get::<Option<i64>, _>("address_id")?, None);
+        assert_eq!(row.try_get::<i64, _>("total")?, 299 + 2 * 600);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use apilib::{
+    set_response, set_response_v2,
+    validate::{validate, Validate, Validator},
+};
+use dblib::users::{
+    auth_events::Event,
+    password_resets::PasswordReset,
+    tokens::RefreshToken,
+    users::{Password, User},
+};
+use hyper::{Body, Response, StatusCode};
+use redis::Client as RedisClient;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::{env, net::IpAddr, sync::Arc};
+use towerlib::auth::revoke_user;
+
+use crate::{
+    audit::audit,
+    mailer::{Email, Mailer},
+    throttle::Throttle,
+    token::{gen_opaque_token, hash_opaque_token, PASSWORD_RESET_TTL},
+    too_many_requests, MAX_EMAIL_LENGTH, MAX_TOKEN_LENGTH,
+};
+
+#[derive(Deserialize)]
+struct ForgotPasswordRequest {
+    email: String,
+}
+
+impl Validate for ForgotPasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email)
+            .max_length("email", &self.email, MAX_EMAIL_LENGTH);
+    }
+}
+
+/// Emails a reset link if the user exists, responds the same either way so
+/// this can't be used to find out who has an account. The email is sent in
+/// the background so the response takes as long too. Requests are throttled
+/// per email and per IP, see `Throttle::password_reset`.
+pub async fn post_password_forgot(
+    pool: &PgPool,
+    redis: &RedisClient,
+    ip: Option<IpAddr>,
+    mailer: Arc<dyn Mailer>,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: ForgotPasswordRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let throttle = Throttle::password_reset();
+
+    let wait = throttle.check(redis, &r.email, ip).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Some(wait) = wait {
+        return Ok(too_many_requests(response, wait));
+    }
+
+    throttle.failure(redis, &r.email, ip).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    tokio::spawn(send_password_reset(pool.clone(), mailer, r.email));
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() =
+        Body::from(r#"{"message": "If the email has an account, a reset link has been sent"}"#);
+
+    Ok(response)
+}
+
+/// Creates a reset token and emails its link, if there's a user with the
+/// email. Runs after `post_password_forgot` has responded, so errors are only
+/// logged.
+async fn send_password_reset(pool: PgPool, mailer: Arc<dyn Mailer>, email: String) {
+    let user = match User::from_email(&pool, &email).await {
+        Ok(u) => u,
+        Err(sqlx::Error::RowNotFound) => return,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+
+    let (token, hash) = gen_opaque_token();
+    if let Err(e) = PasswordReset::create(&pool, user.id, &hash, PASSWORD_RESET_TTL).await {
+        log::error!("{}", e);
+        return;
+    }
+
+    // The API only takes the new password as a POST, so the link is to the
+    // front end's form:
+    let url = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:3000".into());
+    let email = Email {
+        to: user.email,
+        subject: "Reset your password".into(),
+        body: format!(
+            "Use the link below to reset your password, it expires in an hour:\n\n\
+            {}/password/reset?token={}\n\n\
+            If you didn't ask to reset your password you can ignore this email.",
+            url, token
+        ),
+    };
+
+    if let Err(e) = mailer.send(email).await {
+        log::error!("{}", e);
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("token", &self.token)
+            .max_length("token", &self.token, MAX_TOKEN_LENGTH);
+        v.password("password", &self.password);
+    }
+}
+
+pub async fn post_password_reset(
+    pool: &PgPool,
+    redis: &RedisClient,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: ResetPasswordRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let hash = hash_opaque_token(&r.token);
+    let user_id = match PasswordReset::consume(pool, &hash).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return Ok(set_response(
+                response,
+                StatusCode::BAD_REQUEST,
+                Some(r#"{"message": "Invalid or expired reset token"}"#),
+            ))
+        }
+        Err(e) => {
+            log::debug!("{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let user = User::from_id(pool, user_id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let password = Password::hash_async(r.password).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    user.set_password(pool, password).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Whoever had the old password shouldn't stay logged in:
+    PasswordReset::clear(pool, user.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    RefreshToken::revoke_user(pool, user.id)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    revoke_user(redis, user.id).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    audit(&mut response, Event::PasswordReset, Some(user.id), None);
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(r#"{"message": "success"}"#);
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use apilib::App;
+    use hyper::{Body, Response, StatusCode};
+    use redis::Client as RedisClient;
+    use std::sync::Arc;
+
+    use super::{post_password_forgot, post_password_reset, send_password_reset};
+    use crate::{
+        mailer::{FileMailer, LogMailer},
+        post_token,
+        token::use_test_keys,
+    };
+    #[sqlx::test(fixtures("users"))]
+    async fn test_password_reset(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let path = std::env::temp_dir().join(format!("mail-{}.jsonl", uuid::Uuid::new_v4()));
+        let mailer = Arc::new(FileMailer::new(&path));
+
+        // Unknown emails get no email:
+        send_password_reset(app.pool.clone(), mailer.clone(), "bob@smith.com".into()).await;
+        send_password_reset(app.pool.clone(), mailer.clone(), "alice@smith.com".into()).await;
+
+        let emails = mailer.read().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, "bob@smith.com");
+
+        let token = emails[0]
+            .body
+            .split("token=")
+            .nth(1)
+            .and_then(|t| t.split_whitespace().next())
+            .unwrap();
+
+        let reset = || {
+            Body::from(format!(
+                "{{\"token\": \"{}\", \"password\": \"password123\"}}",
+                token
+            ))
+        };
+
+        let response = Response::new(Body::empty());
+        let res = post_password_reset(&app.pool, &redis, &mut reset(), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Tokens are single use:
+        let response = Response::new(Body::empty());
+        let res = post_password_reset(&app.pool, &redis, &mut reset(), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let mut body = Body::from(
+            "\
+{
+    \"email\": \"bob@smith.com\",
+    \"password\": \"password123\"
+}",
+        );
+
+        let response = Response::new(Body::empty());
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_password_forgot(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let forgot = |email: &str| Body::from(format!("{{\"email\": \"{}\"}}", email));
+
+        // Unknown emails get the same response as bob's. A new one each run,
+        // its requests are counted in redis:
+        let email = format!("{}@smith.com", uuid::Uuid::new_v4());
+        for _ in 0..3 {
+            let response = Response::new(Body::empty());
+            let res = post_password_forgot(
+                &pool,
+                &redis,
+                None,
+                Arc::new(LogMailer),
+                &mut forgot(&email),
+                response,
+            )
+            .await
+            .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // The address can't be flooded with links:
+        let response = Response::new(Body::empty());
+        let res = post_password_forgot(
+            &pool,
+            &redis,
+            None,
+            Arc::new(LogMailer),
+            &mut forgot(&email),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+}
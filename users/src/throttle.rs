@@ -0,0 +1,277 @@
+use redis::{AsyncCommands, Client as RedisClient};
+use std::{env, net::IpAddr};
+
+/// Failures allowed before attempts are delayed.
+const FREE_ATTEMPTS: u64 = 2;
+
+/// Longest delay, in seconds, between attempts before an account is locked.
+const MAX_DELAY: u64 = 60;
+
+/// Counts failed logins in Redis per email and per client IP. Each failure
+/// past `FREE_ATTEMPTS` doubles the wait before the next attempt, and
+/// `max_attempts` failures for an email locks that account for `lockout`
+/// seconds. Counts are forgotten `window` seconds after the last failure.
+/// `action` keeps the counts of different actions apart.
+pub struct Throttle {
+    pub action: &'static str,
+    pub max_attempts: u64,
+    pub lockout: u64,
+    pub window: u64,
+}
+
+impl Throttle {
+    /// Reads `LOGIN_MAX_ATTEMPTS`, `LOGIN_LOCKOUT_SECONDS` and
+    /// `LOGIN_WINDOW_SECONDS`, falling back to 5 attempts, 15 minutes and 15
+    /// minutes.
+    pub fn from_env() -> Self {
+        let var = |key: &str, default: u64| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            action: "login",
+            max_attempts: var("LOGIN_MAX_ATTEMPTS", 5),
+            lockout: var("LOGIN_LOCKOUT_SECONDS", 15 * 60),
+            window: var("LOGIN_WINDOW_SECONDS", 15 * 60),
+        }
+    }
+
+    /// Counts every request for a reset link, so an address can't be flooded
+    /// with them. Its counts don't affect logins.
+    pub fn password_reset() -> Self {
+        Self {
+            action: "reset",
+            max_attempts: 5,
+            lockout: 60 * 60,
+            window: 60 * 60,
+        }
+    }
+
+    /// Returns the seconds until another attempt is allowed, if the email or
+    /// IP has to wait.
+    pub async fn check(
+        &self,
+        redis: &RedisClient,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> redis::RedisResult<Option<u64>> {
+        let email = email.to_lowercase();
+        let mut con = redis.get_async_connection().await?;
+
+        let mut pipe = redis::pipe();
+        pipe.ttl(self.lock_key(&email)).ttl(self.delay_key(&email));
+        if let Some(ip) = ip {
+            pipe.ttl(self.ip_delay_key(ip));
+        }
+
+        // TTL is negative for missing keys:
+        let ttls: Vec<i64> = pipe.query_async(&mut con).await?;
+
+        Ok(ttls.into_iter().max().filter(|t| *t > 0).map(|t| t as u64))
+    }
+
+    /// Records a failed attempt. Returns the lockout in seconds if this
+    /// failure locked the account.
+    pub async fn failure(
+        &self,
+        redis: &RedisClient,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> redis::RedisResult<Option<u64>> {
+        let email = email.to_lowercase();
+        let mut con = redis.get_async_connection().await?;
+
+        let failures = self
+            .count(&mut con, self.failures_key(&email), self.delay_key(&email))
+            .await?;
+        if let Some(ip) = ip {
+            self.count(&mut con, self.ip_failures_key(ip), self.ip_delay_key(ip))
+                .await?;
+        }
+
+        if failures < self.max_attempts {
+            return Ok(None);
+        }
+
+        redis::pipe()
+            .set_ex(self.lock_key(&email), 1, self.lockout as usize)
+            .ignore()
+            .del(&[self.failures_key(&email), self.delay_key(&email)])
+            .ignore()
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        log::warn!(
+            "Locked {} out of {} for {}s after {} attempts, last from {}",
+            email,
+            self.action,
+            self.lockout,
+            failures,
+            ip.map_or("unknown".into(), |ip| ip.to_string())
+        );
+
+        Ok(Some(self.lockout))
+    }
+
+    /// Clears the failures for an email after a successful login. The IP's
+    /// count is kept, so one valid account can't be used to reset it.
+    pub async fn success(&self, redis: &RedisClient, email: &str) -> redis::RedisResult<()> {
+        let email = email.to_lowercase();
+        let mut con = redis.get_async_connection().await?;
+
+        con.del(&[self.failures_key(&email), self.delay_key(&email)])
+            .await
+    }
+
+    /// Unlocks an account early. Returns false if it wasn't locked.
+    pub async fn unlock(&self, redis: &RedisClient, email: &str) -> redis::RedisResult<bool> {
+        let email = email.to_lowercase();
+        let mut con = redis.get_async_connection().await?;
+
+        let (locked, _): (u64, u64) = redis::pipe()
+            .del(self.lock_key(&email))
+            .del(&[self.failures_key(&email), self.delay_key(&email)])
+            .query_async(&mut con)
+            .await?;
+
+        Ok(locked > 0)
+    }
+
+    /// Increments the failures and sets the delay before the next attempt.
+    /// Returns the failures so far.
+    async fn count(
+        &self,
+        con: &mut redis::aio::Connection,
+        failures_key: String,
+        delay_key: String,
+    ) -> redis::RedisResult<u64> {
+        let (failures,): (u64,) = redis::pipe()
+            .incr(&failures_key, 1)
+            .expire(&failures_key, self.window as usize)
+            .ignore()
+            .query_async(con)
+            .await?;
+
+        let delay = delay(failures);
+        if delay > 0 {
+            con.set_ex::<_, _, ()>(delay_key, 1, delay as usize).await?;
+        }
+
+        Ok(failures)
+    }
+
+    // Emails and IPs are kept apart, an email can't be made to look like an
+    // IP:
+    fn failures_key(&self, email: &str) -> String {
+        format!("{}:failures:email:{}", self.action, email)
+    }
+
+    fn ip_failures_key(&self, ip: IpAddr) -> String {
+        format!("{}:failures:ip:{}", self.action, ip)
+    }
+
+    fn delay_key(&self, email: &str) -> String {
+        format!("{}:delay:email:{}", self.action, email)
+    }
+
+    fn ip_delay_key(&self, ip: IpAddr) -> String {
+        format!("{}:delay:ip:{}", self.action, ip)
+    }
+
+    fn lock_key(&self, email: &str) -> String {
+        format!("{}:lock:{}", self.action, email)
+    }
+}
+
+/// Seconds to wait after `failures` failed attempts.
+fn delay(failures: u64) -> u64 {
+    match failures.checked_sub(FREE_ATTEMPTS + 1) {
+        Some(n) => 1u64
+            .checked_shl(n as u32)
+            .unwrap_or(MAX_DELAY)
+            .min(MAX_DELAY),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{delay, Throttle};
+    use redis::Client as RedisClient;
+    use std::net::{IpAddr, Ipv4Addr};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(delay(1), 0);
+        assert_eq!(delay(2), 0);
+        assert_eq!(delay(3), 1);
+        assert_eq!(delay(4), 2);
+        assert_eq!(delay(5), 4);
+        assert_eq!(delay(100), 60);
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let throttle = Throttle {
+            action: "login",
+            max_attempts: 3,
+            lockout: 60,
+            window: 60,
+        };
+
+        let email = format!("{}@smith.com", Uuid::new_v4());
+        let [a, b, c, d] = rand::random::<[u8; 4]>();
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+
+        assert_eq!(throttle.check(&redis, &email, None).await.unwrap(), None);
+
+        assert_eq!(throttle.failure(&redis, &email, ip).await.unwrap(), None);
+        assert_eq!(throttle.failure(&redis, &email, ip).await.unwrap(), None);
+        assert_eq!(
+            throttle.failure(&redis, &email, ip).await.unwrap(),
+            Some(60)
+        );
+
+        let wait = throttle.check(&redis, &email, None).await.unwrap();
+        assert!(matches!(wait, Some(w) if w > 1));
+
+        assert!(throttle.unlock(&redis, &email).await.unwrap());
+        assert!(!throttle.unlock(&redis, &email).await.unwrap());
+        assert_eq!(throttle.check(&redis, &email, None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_email_and_ip_kept_apart() {
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let throttle = Throttle {
+            action: "login",
+            max_attempts: 10,
+            lockout: 60,
+            window: 60,
+        };
+
+        // An "email" that is the same string as the IP:
+        let [a, b, c, d] = rand::random::<[u8; 4]>();
+        let ip = IpAddr::V4(Ipv4Addr::new(a, b, c, d));
+        let email = ip.to_string();
+        for _ in 0..3 {
+            throttle.failure(&redis, &email, None).await.unwrap();
+        }
+        assert!(throttle
+            .check(&redis, &email, None)
+            .await
+            .unwrap()
+            .is_some());
+
+        let other = format!("{}@smith.com", Uuid::new_v4());
+        assert_eq!(
+            throttle.check(&redis, &other, Some(ip)).await.unwrap(),
+            None
+        );
+    }
+}
@@ -1,46 +1,172 @@
-use std::{collections::BTreeMap, env};
-
 use dblib::users::users::User;
-use hmac::{Hmac, Mac};
 use hyper::http::HeaderValue;
-use jwt::{SignWithKey, VerifyWithKey};
-use sha2::Sha256;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use towerlib::{
+    auth::{now, Claims, Role},
+    keys::signing_keys,
+};
+
+/// Seconds a refresh token is valid for, each one can only be used once.
+pub const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+
+/// Seconds a password reset token is valid for.
+pub const PASSWORD_RESET_TTL: i64 = 60 * 60;
+
+/// Seconds an email verification link is valid for.
+pub const VERIFY_EMAIL_TTL: u64 = 24 * 60 * 60;
+
+/// Seconds a user has to enter their 2FA code after their password.
+pub const CHALLENGE_TTL: u64 = 5 * 60;
+
+const VERIFY_EMAIL: &str = "verify-email";
+const CHALLENGE: &str = "2fa";
 
 #[derive(Debug)]
 pub enum TokenError {
-    Hmac,
     Sign,
     Verify,
+    Expired,
 }
 
-fn get_key() -> Result<Hmac<Sha256>, TokenError> {
-    let key = env::var("TOKEN_SECRET").expect("TOKEN_SECRET must be set");
-    Hmac::new_from_slice(key.as_bytes()).map_err(|_e| TokenError::Hmac)
+/// Claims for single purpose tokens, like the signed link sent to verify an
+/// email address. `purpose` keeps these from being used as access tokens, or
+/// for another purpose.
+#[derive(Serialize, Deserialize)]
+struct PurposeClaims {
+    id: i64,
+    email: String,
+    purpose: String,
+    exp: u64,
 }
 
-pub fn gen_token(user: &User) -> Result<HeaderValue, TokenError> {
-    let key = get_key()?;
-
-    let claims: BTreeMap<&str, String> = BTreeMap::from([
-        ("id", user.id.to_string()),
-        ("email", user.email.to_owned()),
-        ("role", "user".to_owned()),
-    ]);
+pub fn gen_token(user: &User, role: Role) -> Result<HeaderValue, TokenError> {
+    let verified = user.email_verified_at.is_some();
+    let claims = Claims::new(user.id, user.email.to_owned(), role, verified);
 
-    let token = claims
-        .sign_with_key(&key)
+    let token = signing_keys()
+        .sign(&claims)
         .map(|t| HeaderValue::from_str(&t).unwrap())
         .map_err(|_| TokenError::Sign)?;
 
     Ok(token)
 }
 
-pub fn verify_token(token: &str) -> Result<BTreeMap<String, String>, TokenError> {
-    let key = get_key()?;
+fn gen_purpose_token(user: &User, purpose: &str, ttl: u64) -> Result<String, TokenError> {
+    let claims = PurposeClaims {
+        id: user.id,
+        email: user.email.to_owned(),
+        purpose: purpose.to_owned(),
+        exp: now() + ttl,
+    };
 
-    let token: BTreeMap<String, String> = token
-        .verify_with_key(&key)
+    signing_keys().sign(&claims).map_err(|_| TokenError::Sign)
+}
+
+fn verify_purpose_token(token: &str, purpose: &str) -> Result<(i64, String), TokenError> {
+    let claims: PurposeClaims = signing_keys()
+        .verify(token)
         .map_err(|_| TokenError::Verify)?;
 
-    Ok(token)
+    if claims.purpose != purpose {
+        return Err(TokenError::Verify);
+    }
+
+    if claims.exp <= now() {
+        return Err(TokenError::Expired);
+    }
+
+    Ok((claims.id, claims.email))
+}
+
+pub fn gen_verify_email_token(user: &User) -> Result<String, TokenError> {
+    gen_purpose_token(user, VERIFY_EMAIL, VERIFY_EMAIL_TTL)
+}
+
+/// Returns the user ID and the email address the link was sent to.
+pub fn verify_email_token(token: &str) -> Result<(i64, String), TokenError> {
+    verify_purpose_token(token, VERIFY_EMAIL)
+}
+
+/// Returns the challenge for the second step of a login with 2FA on.
+pub fn gen_challenge_token(user: &User) -> Result<String, TokenError> {
+    gen_purpose_token(user, CHALLENGE, CHALLENGE_TTL)
+}
+
+/// Returns the user ID and email the challenge was issued for.
+pub fn verify_challenge_token(token: &str) -> Result<(i64, String), TokenError> {
+    verify_purpose_token(token, CHALLENGE)
+}
+
+/// Returns a random token and the hash to store for it, for refresh and
+/// password reset tokens.
+pub fn gen_opaque_token() -> (String, Vec<u8>) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let hash = hash_opaque_token(&token);
+
+    (token, hash)
+}
+
+pub fn hash_opaque_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Signs with the fixture key in tests. They run in parallel, so the key is
+/// set once here rather than through `JWT_PRIVATE_KEYS`.
+#[cfg(test)]
+pub(crate) fn use_test_keys() {
+    use towerlib::keys::{set_signing_keys, SigningKeys};
+
+    const KEY: &[u8] = include_bytes!("../../towerlib/src/fixtures/jwt-1.pem");
+
+    let _ = set_signing_keys(SigningKeys::from_pem(&[KEY]).unwrap());
+}
+
+#[cfg(test)]
+mod test {
+    use towerlib::{
+        auth::{now, Claims, Role},
+        keys::signing_keys,
+    };
+
+    use super::{
+        use_test_keys, verify_challenge_token, verify_email_token, PurposeClaims, TokenError,
+        VERIFY_EMAIL, VERIFY_EMAIL_TTL,
+    };
+
+    #[test]
+    fn test_access_token_is_not_a_verify_email_token() {
+        use_test_keys();
+
+        let claims = Claims::new(1, "bob@smith.com".into(), Role::User, false);
+        let token = signing_keys().sign(&claims).unwrap();
+
+        assert!(matches!(
+            verify_email_token(&token),
+            Err(TokenError::Verify)
+        ));
+    }
+
+    #[test]
+    fn test_verify_email_token_is_not_a_challenge() {
+        use_test_keys();
+
+        let claims = PurposeClaims {
+            id: 1,
+            email: "bob@smith.com".into(),
+            purpose: VERIFY_EMAIL.into(),
+            exp: now() + VERIFY_EMAIL_TTL,
+        };
+        let token = signing_keys().sign(&claims).unwrap();
+
+        assert!(matches!(
+            verify_challenge_token(&token),
+            Err(TokenError::Verify)
+        ));
+        assert!(verify_email_token(&token).is_ok());
+    }
 }
@@ -0,0 +1,155 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+/// Seconds each code is valid for.
+pub const STEP: u64 = 30;
+
+/// Codes from this many steps either side of now are accepted, for clock
+/// drift.
+const SKEW: u64 = 1;
+
+const DIGITS: u32 = 6;
+
+const ISSUER: &str = "teapot";
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// Returns a random 160 bit secret, the size RFC 4226 recommends.
+pub fn gen_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    secret
+}
+
+/// Base32, the format authenticator apps expect when typed in.
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(ALPHABET, secret)
+}
+
+/// The URI for the QR code authenticator apps scan.
+pub fn otpauth_uri(secret: &[u8], email: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{email}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        email = encode_uri_component(email),
+        secret = encode_secret(secret),
+        digits = DIGITS,
+        period = STEP,
+    )
+}
+
+/// The code for a time step, as in RFC 6238.
+pub fn code(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC can take keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation:
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Returns the time step `code` is valid for, if it is valid at `time`.
+/// Callers should reject steps that have already been used.
+pub fn verify(secret: &[u8], code: &str, time: u64) -> Option<u64> {
+    let current = time / STEP;
+
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| {
+        let expected = self::code(secret, *step);
+        // Compare every byte, so the time taken doesn't give anything away:
+        expected.len() == code.len()
+            && expected
+                .bytes()
+                .zip(code.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    })
+}
+
+/// Returns `n` random recovery codes, formatted like `1a2b3-c4d5e`.
+pub fn gen_recovery_codes(n: usize) -> Vec<String> {
+    (0..n)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}", &hex[..5], &hex[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without the dash and case insensitively.
+pub fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn encode_uri_component(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{code, normalise_recovery_code, otpauth_uri, verify, STEP};
+
+    // The SHA1 secret from the RFC 6238 test vectors:
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_code() {
+        // The RFC uses 8 digits, these are the last 6:
+        assert_eq!(code(SECRET, 59 / STEP), "287082");
+        assert_eq!(code(SECRET, 1111111109 / STEP), "081804");
+        assert_eq!(code(SECRET, 1234567890 / STEP), "005924");
+    }
+
+    #[test]
+    fn test_verify() {
+        let time = 1234567890;
+        let step = time / STEP;
+
+        assert_eq!(verify(SECRET, "005924", time), Some(step));
+        assert_eq!(
+            verify(SECRET, &code(SECRET, step - 1), time),
+            Some(step - 1)
+        );
+        assert_eq!(verify(SECRET, &code(SECRET, step + 2), time), None);
+        assert_eq!(verify(SECRET, "00592", time), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let uri = otpauth_uri(SECRET, "bob+2fa@smith.com");
+        assert!(uri.starts_with("otpauth://totp/teapot:bob%2B2fa%40smith.com?"));
+        assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+    }
+
+    #[test]
+    fn test_normalise_recovery_code() {
+        assert_eq!(normalise_recovery_code(" 1A2B3-c4d5e "), "1a2b3c4d5e");
+    }
+}
@@ -0,0 +1,455 @@
+use apilib::{
+    set_response, set_response_v2,
+    validate::{validate, Validate, Validator},
+};
+use dblib::users::{
+    auth_events::Event,
+    totp::{RecoveryCodes, Totp},
+    users::User,
+};
+use hyper::{http::Extensions, Body, Response, StatusCode};
+use redis::Client as RedisClient;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::net::IpAddr;
+use towerlib::auth::{get_claims, now};
+
+use crate::{
+    audit::audit,
+    login_failed, set_tokens,
+    throttle::Throttle,
+    token::{hash_opaque_token, verify_challenge_token},
+    too_many_requests, totp, unauthorized, MAX_CODE_LENGTH, MAX_TOKEN_LENGTH,
+};
+
+/// Recovery codes given out when 2FA is turned on.
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Deserialize)]
+struct TwoFactorRequest {
+    challenge: String,
+    code: Option<String>,
+    #[serde(rename(deserialize = "recoveryCode"))]
+    recovery_code: Option<String>,
+}
+
+impl Validate for TwoFactorRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("challenge", &self.challenge).max_length(
+            "challenge",
+            &self.challenge,
+            MAX_TOKEN_LENGTH,
+        );
+        if let Some(code) = &self.code {
+            v.max_length("code", code, MAX_CODE_LENGTH);
+        }
+        if let Some(code) = &self.recovery_code {
+            v.max_length("recoveryCode", code, MAX_CODE_LENGTH);
+        }
+    }
+}
+
+/// Second step of a login with 2FA on, takes the challenge from `post_token`
+/// and either a code from the authenticator or a recovery code.
+pub async fn post_token_2fa(
+    pool: &PgPool,
+    redis: &RedisClient,
+    ip: Option<IpAddr>,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: TwoFactorRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let (id, email) = match verify_challenge_token(&r.challenge) {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("{:?}", e);
+            return Ok(set_response(
+                response,
+                StatusCode::UNAUTHORIZED,
+                Some(r#"{"message": "Challenge is invalid or has expired"}"#),
+            ));
+        }
+    };
+
+    let throttle = Throttle::from_env();
+
+    let wait = throttle.check(redis, &email, ip).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Some(wait) = wait {
+        return Ok(too_many_requests(response, wait));
+    }
+
+    let totp = Totp::get(pool, id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let totp = match totp {
+        Some(t) if t.confirmed => t,
+        _ => {
+            return Ok(set_response(
+                response,
+                StatusCode::UNAUTHORIZED,
+                Some(r#"{"message": "Challenge is invalid or has expired"}"#),
+            ))
+        }
+    };
+
+    let passed = match (&r.code, &r.recovery_code) {
+        (Some(code), _) => match totp::verify(&totp.secret, code.trim(), now()) {
+            Some(step) => totp.use_step(pool, step as i64).await,
+            None => Ok(false),
+        },
+        (None, Some(code)) => {
+            let hash = hash_opaque_token(&totp::normalise_recovery_code(code));
+            RecoveryCodes::consume(pool, id, &hash).await
+        }
+        (None, None) => Ok(false),
+    }
+    .map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !passed {
+        return login_failed(
+            &throttle,
+            redis,
+            &email,
+            ip,
+            r#"{"message": "Code is incorrect"}"#,
+            response,
+        )
+        .await;
+    }
+
+    throttle.success(redis, &email).await.map_err(|e| {
+        log::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let user = User::from_id(pool, id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    response = set_tokens(pool, &user, None, response).await?;
+    audit(
+        &mut response,
+        Event::Login,
+        Some(user.id),
+        Some(&user.email),
+    );
+
+    let res = serde_json::to_string(&user).unwrap();
+    *response.body_mut() = Body::from(res);
+
+    Ok(response)
+}
+
+/// Starts turning 2FA on. The secret isn't used for logins until it has
+/// been confirmed with `post_2fa_confirm`.
+pub async fn post_2fa_enroll(
+    pool: &PgPool,
+    extensions: &Extensions,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let secret = totp::gen_secret();
+
+    let enrolled = Totp::enroll(pool, claims.id, &secret).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !enrolled {
+        return Ok(set_response(
+            response,
+            StatusCode::CONFLICT,
+            Some(r#"{"message": "Two-factor authentication is already on"}"#),
+        ));
+    }
+
+    let res = serde_json::json!({
+        "secret": totp::encode_secret(&secret),
+        "uri": totp::otpauth_uri(&secret, &claims.email),
+    });
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res.to_string());
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct ConfirmTwoFactorRequest {
+    code: String,
+}
+
+impl Validate for ConfirmTwoFactorRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("code", &self.code)
+            .max_length("code", &self.code, MAX_CODE_LENGTH);
+    }
+}
+
+/// Turns 2FA on and returns the recovery codes, this is the only time they
+/// are shown.
+pub async fn post_2fa_confirm(
+    pool: &PgPool,
+    extensions: &Extensions,
+    body: &mut Body,
+    mut response: Response<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let claims = match get_claims(extensions) {
+        Ok(c) => c,
+        Err(_) => return Ok(unauthorized(response)),
+    };
+
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let r: ConfirmTwoFactorRequest = serde_json::from_slice(&bytes).map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    if let Err(e) = validate(&r) {
+        return Ok(set_response_v2(response, e));
+    }
+
+    let totp = Totp::get(pool, claims.id).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let totp = match totp {
+        Some(t) if !t.confirmed => t,
+        Some(_) => {
+            return Ok(set_response(
+                response,
+                StatusCode::CONFLICT,
+                Some(r#"{"message": "Two-factor authentication is already on"}"#),
+            ))
+        }
+        None => {
+            return Ok(set_response(
+                response,
+                StatusCode::NOT_FOUND,
+                Some(r#"{"message": "Two-factor authentication has not been set up"}"#),
+            ))
+        }
+    };
+
+    let step = match totp::verify(&totp.secret, r.code.trim(), now()) {
+        Some(step) => step,
+        None => {
+            return Ok(set_response(
+                response,
+                StatusCode::FORBIDDEN,
+                Some(r#"{"message": "Code is incorrect"}"#),
+            ))
+        }
+    };
+
+    let confirmed = totp.confirm(pool, step as i64).await.map_err(|e| {
+        log::debug!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !confirmed {
+        // Confirmed by another request in the meantime:
+        return Ok(set_response(
+            response,
+            StatusCode::CONFLICT,
+            Some(r#"{"message": "Two-factor authentication is already on"}"#),
+        ));
+    }
+
+    let codes = totp::gen_recovery_codes(RECOVERY_CODES);
+    let hashes: Vec<Vec<u8>> = codes
+        .iter()
+        .map(|c| hash_opaque_token(&totp::normalise_recovery_code(c)))
+        .collect();
+
+    RecoveryCodes::replace(pool, claims.id, &hashes)
+        .await
+        .map_err(|e| {
+            log::debug!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let res = serde_json::json!({ "recoveryCodes": codes });
+
+    *response.status_mut() = StatusCode::OK;
+    *response.body_mut() = Body::from(res.to_string());
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use apilib::App;
+    use dblib::users::{roles::Roles, totp::Totp};
+    use hyper::{Body, Response, StatusCode};
+    use redis::Client as RedisClient;
+    use towerlib::auth::{check_permission, decode_token, now, Permission, Role};
+    use towerlib::keys::signing_keys;
+
+    use super::{post_2fa_confirm, post_2fa_enroll, post_token_2fa};
+    use crate::{admin::post_roles, bob, post_token, token::use_test_keys, totp};
+    #[sqlx::test(fixtures("users"))]
+    async fn test_two_factor_login(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+
+        let response = Response::new(Body::empty());
+        let res = post_2fa_enroll(&app.pool, &bob(), response).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let totp = Totp::get(&app.pool, 1).await?.unwrap();
+        assert!(!totp.confirmed);
+
+        let code = totp::code(&totp.secret, now() / totp::STEP);
+        let mut body = Body::from(format!("{{\"code\": \"{}\"}}", code));
+        let response = Response::new(Body::empty());
+        let res = post_2fa_confirm(&app.pool, &bob(), &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let recovery_code = res["recoveryCodes"][0].as_str().unwrap().to_owned();
+
+        // The password alone only gets a challenge:
+        let mut body = Body::from("{\"email\": \"bob@smith.com\", \"password\": \"password\"}");
+        let response = Response::new(Body::empty());
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("token").is_none());
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let challenge = res["challenge"].as_str().unwrap().to_owned();
+
+        // The code used to confirm can't be used again:
+        let mut body = Body::from(format!(
+            "{{\"challenge\": \"{}\", \"code\": \"{}\"}}",
+            challenge, code
+        ));
+        let response = Response::new(Body::empty());
+        let res = post_token_2fa(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let second_step = |recovery_code: &str| {
+            Body::from(format!(
+                "{{\"challenge\": \"{}\", \"recoveryCode\": \"{}\"}}",
+                challenge, recovery_code
+            ))
+        };
+
+        let response = Response::new(Body::empty());
+        let res = post_token_2fa(
+            &app.pool,
+            &redis,
+            None,
+            &mut second_step(&recovery_code),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("token").is_some());
+
+        // Recovery codes only work once:
+        let response = Response::new(Body::empty());
+        let res = post_token_2fa(
+            &app.pool,
+            &redis,
+            None,
+            &mut second_step(&recovery_code),
+            response,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_admin_needs_two_factor(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        use_test_keys();
+
+        let app = App::new(pool, None);
+        let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
+        let grant = || Body::from("{\"userId\": 1, \"role\": \"admin\"}");
+
+        let response = Response::new(Body::empty());
+        let res = post_roles(&app.pool, &redis, &mut grant(), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // An admin from before 2FA was needed only gets a user token, so
+        // can't reach admin routes:
+        Roles::grant(&app.pool, 1, "admin").await?;
+        let mut body = Body::from("{\"email\": \"bob@smith.com\", \"password\": \"password\"}");
+        let response = Response::new(Body::empty());
+        let res = post_token(&app.pool, &redis, None, &mut body, response)
+            .await
+            .unwrap();
+        let token = res.headers().get("token").unwrap().to_str().unwrap();
+        let claims = decode_token(&signing_keys().verifier(), token)
+            .await
+            .unwrap();
+        assert_eq!(claims.role, Role::User);
+        let (code, _) = check_permission(Permission::Admin, Some(&claims)).unwrap_err();
+        assert_eq!(code, StatusCode::FORBIDDEN);
+
+        let response = Response::new(Body::empty());
+        post_2fa_enroll(&app.pool, &bob(), response).await.unwrap();
+        let totp = Totp::get(&app.pool, 1).await?.unwrap();
+        let code = totp::code(&totp.secret, now() / totp::STEP);
+        let mut body = Body::from(format!("{{\"code\": \"{}\"}}", code));
+        let response = Response::new(Body::empty());
+        post_2fa_confirm(&app.pool, &bob(), &mut body, response)
+            .await
+            .unwrap();
+
+        let response = Response::new(Body::empty());
+        let res = post_roles(&app.pool, &redis, &mut grant(), response)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+}